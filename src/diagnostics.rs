@@ -0,0 +1,217 @@
+use crate::keybuffer::KeycodeBuffer;
+use crate::mcu::pac;
+use core::cell::Cell;
+use msp430::interrupt::{CriticalSection, Mutex};
+
+static RESET_REASON: Mutex<Cell<ResetReason>> = Mutex::new(Cell::new(ResetReason::Unknown));
+static FSM_ERRORS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+static TIMER_FAILED: Mutex<Cell<bool>> = Mutex::new(Cell::new(false));
+static DROPPED_XT_KEYS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+#[cfg(feature = "timing-diag")]
+static AT_CLOCK_PERIOD: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+#[cfg(feature = "profiling")]
+static LOOP_TIME: Mutex<Cell<Durations>> = Mutex::new(Cell::new(Durations::new()));
+#[cfg(feature = "profiling")]
+static ISR_TIME: Mutex<Cell<Durations>> = Mutex::new(Cell::new(Durations::new()));
+
+// Which cargo features this firmware was built with, one bit each, for bug
+// reports from custom builds. Append new features; don't renumber.
+const fn flag(enabled: bool, bit: u8) -> u32 {
+    if enabled {
+        1 << bit
+    } else {
+        0
+    }
+}
+
+pub const FEATURE_FLAGS: u32 = flag(cfg!(feature = "boot-replay"), 0)
+    | flag(cfg!(feature = "xt-parity"), 1)
+    | flag(cfg!(feature = "collapse-repeats"), 2)
+    | flag(cfg!(feature = "at-debounce"), 3)
+    | flag(cfg!(feature = "self-test-status"), 4)
+    | flag(cfg!(feature = "instant-delay"), 5)
+    | flag(cfg!(feature = "forward-gui-keys"), 6)
+    | flag(cfg!(feature = "event-log"), 7)
+    | flag(cfg!(feature = "leds"), 8)
+    | flag(cfg!(feature = "kbd-powersave"), 9)
+    | flag(cfg!(feature = "prefer-keyboard-set1"), 10)
+    | flag(cfg!(feature = "strict-framing"), 11)
+    | flag(cfg!(feature = "loopback-test"), 12)
+    | flag(cfg!(feature = "macros"), 13)
+    | flag(cfg!(feature = "boot-heartbeat"), 14)
+    | flag(cfg!(feature = "timing-diag"), 15)
+    | flag(cfg!(feature = "boot-numlock-on"), 16)
+    | flag(cfg!(feature = "strict-xt-timing"), 17)
+    | flag(cfg!(feature = "profiling"), 18)
+    | flag(cfg!(feature = "scroll-lock-as-mode"), 19);
+
+#[allow(dead_code)]
+pub fn feature_flags() -> u32 {
+    FEATURE_FLAGS
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    ResetPin,
+    Watchdog,
+    Unknown,
+}
+
+impl ResetReason {
+    // Decode the reset flags of IFG1. Power-on clears WDTIFG, but PORIFG and
+    // RSTIFG stay set until software clears them, so check WDTIFG first.
+    pub fn from_ifg1(r: &pac::special_function::ifg1::R) -> ResetReason {
+        if r.wdtifg().bit_is_set() {
+            ResetReason::Watchdog
+        } else if r.porifg().bit_is_set() {
+            ResetReason::PowerOn
+        } else if r.rstifg().bit_is_set() {
+            ResetReason::ResetPin
+        } else {
+            ResetReason::Unknown
+        }
+    }
+}
+
+pub fn set_reset_reason(cs: CriticalSection, reason: ResetReason) {
+    RESET_REASON.borrow(cs).set(reason);
+}
+
+#[allow(dead_code)]
+pub fn reset_reason(cs: CriticalSection) -> ResetReason {
+    RESET_REASON.borrow(cs).get()
+}
+
+// Pack the reset reason (bits 0-1), whether the timer failed its boot check
+// (bit 2), and a buffer high-water mark (bits 4-7) into a byte to send after
+// SELF_TEST_PASSED.
+#[cfg(feature = "self-test-status")]
+pub fn status_byte(cs: CriticalSection, high_water: u8) -> u8 {
+    let reason = match reset_reason(cs) {
+        ResetReason::Unknown => 0,
+        ResetReason::PowerOn => 1,
+        ResetReason::ResetPin => 2,
+        ResetReason::Watchdog => 3,
+    };
+
+    let timer = if timer_failed(cs) { 0x04 } else { 0 };
+
+    (high_water.min(0x0f) << 4) | timer | reason
+}
+
+pub fn count_fsm_error(cs: CriticalSection) {
+    let errors = FSM_ERRORS.borrow(cs);
+    errors.set(errors.get().saturating_add(1));
+}
+
+// init() found that the timer doesn't count, so waits spin instead.
+pub fn set_timer_failed(cs: CriticalSection) {
+    TIMER_FAILED.borrow(cs).set(true);
+}
+
+pub fn timer_failed(cs: CriticalSection) -> bool {
+    TIMER_FAILED.borrow(cs).get()
+}
+
+// Fold one frame's measured AT clock period into a running average, weighting
+// the new sample 1/8.
+#[cfg(feature = "timing-diag")]
+pub fn record_at_clock_period(cs: CriticalSection, period_us: u16) {
+    let avg = AT_CLOCK_PERIOD.borrow(cs);
+
+    avg.set(match avg.get() {
+        0 => period_us,
+        a => a - a / 8 + period_us / 8,
+    });
+}
+
+// Average AT clock period in us, or 0 before the first measured frame. A
+// keyboard within spec clocks at 10-16.7kHz, i.e. 60-100us.
+#[cfg(feature = "timing-diag")]
+#[allow(dead_code)]
+pub fn at_clock_period(cs: CriticalSection) -> u16 {
+    AT_CLOCK_PERIOD.borrow(cs).get()
+}
+
+// Shortest, longest, and latest of something timed, in timer ticks (10us).
+// min stays at u16::MAX until the first measurement.
+#[cfg(feature = "profiling")]
+#[derive(Clone, Copy)]
+pub struct Durations {
+    pub min: u16,
+    pub max: u16,
+    #[allow(dead_code)]
+    pub last: u16,
+}
+
+#[cfg(feature = "profiling")]
+impl Durations {
+    const fn new() -> Durations {
+        Durations {
+            min: u16::MAX,
+            max: 0,
+            last: 0,
+        }
+    }
+
+    fn record(cell: &Cell<Durations>, ticks: u16) {
+        let d = cell.get();
+
+        cell.set(Durations {
+            min: d.min.min(ticks),
+            max: d.max.max(ticks),
+            last: ticks,
+        });
+    }
+}
+
+// One pass of the main loop, for any command but WaitForKey (which is
+// mostly waiting for a key).
+#[cfg(feature = "profiling")]
+pub fn record_loop_time(cs: CriticalSection, ticks: u16) {
+    Durations::record(LOOP_TIME.borrow(cs), ticks);
+}
+
+// One run of PORT1.
+#[cfg(feature = "profiling")]
+pub fn record_isr_time(cs: CriticalSection, ticks: u16) {
+    Durations::record(ISR_TIME.borrow(cs), ticks);
+}
+
+#[cfg(feature = "profiling")]
+#[allow(dead_code)]
+pub fn loop_time(cs: CriticalSection) -> Durations {
+    LOOP_TIME.borrow(cs).get()
+}
+
+#[cfg(feature = "profiling")]
+#[allow(dead_code)]
+pub fn isr_time(cs: CriticalSection) -> Durations {
+    ISR_TIME.borrow(cs).get()
+}
+
+// A key the host never took was given up on.
+#[cfg_attr(feature = "boot-replay", allow(dead_code))]
+pub fn count_dropped_xt_key(cs: CriticalSection) {
+    let dropped = DROPPED_XT_KEYS.borrow(cs);
+    dropped.set(dropped.get().saturating_add(1));
+}
+
+#[allow(dead_code)]
+pub fn dropped_xt_keys(cs: CriticalSection) -> u8 {
+    DROPPED_XT_KEYS.borrow(cs).get()
+}
+
+#[allow(dead_code)]
+pub fn fsm_errors(cs: CriticalSection) -> u8 {
+    FSM_ERRORS.borrow(cs).get()
+}
+
+// Report a buffer's peak fill level and start measuring afresh.
+#[allow(dead_code)]
+pub fn take_high_water_mark(buf: &mut KeycodeBuffer) -> u8 {
+    let max = buf.high_water_mark();
+    buf.reset_high_water_mark();
+    max
+}
@@ -1,6 +1,9 @@
+use crate::mcu::pac::{
+    self,
+    generic::{Readable, Reg, RegisterSpec, Writable},
+    port_1_2::*,
+};
 use bitflags::bitflags;
-use msp430g2211::generic::{Readable, Reg, RegisterSpec, Writable};
-use msp430g2211::port_1_2::*;
 
 bitflags! {
     #[derive(Clone, Copy)]
@@ -18,6 +21,24 @@ bitflags! {
     }
 }
 
+// Set these on boards where the AT lines pass through an inverting buffer.
+// Reads and writes of inverted pins are flipped here, so the rest of the
+// firmware deals in the levels the keyboard sees.
+const AT_DATA_INVERTED: bool = false;
+const AT_CLK_INVERTED: bool = false;
+
+const fn bits_if(pins: Pins, cond: bool) -> u8 {
+    if cond {
+        pins.bits()
+    } else {
+        0
+    }
+}
+
+const INVERTED: Pins = Pins::from_bits_retain(
+    bits_if(Pins::AT_DATA, AT_DATA_INVERTED) | bits_if(Pins::AT_CLK, AT_CLK_INVERTED),
+);
+
 macro_rules! from_impl_for_pins {
     ($t:ty) => {
         impl From<$t> for Pins {
@@ -34,6 +55,7 @@ from_impl_for_pins! { &p1dir::R }
 from_impl_for_pins! { &p1ifg::R }
 from_impl_for_pins! { &p1ie::R }
 from_impl_for_pins! { &p1ies::R }
+from_impl_for_pins! { &p1ren::R }
 
 trait PortWrite {
     fn bits_w(&mut self, bits: u8) -> &mut Self;
@@ -55,6 +77,7 @@ impl_port_write! { p1dir::W, p1dir }
 impl_port_write! { p1ifg::W, p1ifg }
 impl_port_write! { p1ie::W, p1ie }
 impl_port_write! { p1ies::W, p1ies }
+impl_port_write! { p1ren::W, p1ren }
 
 fn set_port_reg<REG>(reg: &Reg<REG>, pins: Pins)
 where
@@ -80,67 +103,179 @@ where
     });
 }
 
-pub fn set(p: &msp430g2211::PORT_1_2, pins: Pins) {
-    set_port_reg(&p.p1out, pins);
+pub fn set(p: &pac::PORT_1_2, pins: Pins) {
+    set_and_clear(p, pins, Pins::empty());
+}
+
+pub fn unset(p: &pac::PORT_1_2, pins: Pins) {
+    set_and_clear(p, Pins::empty(), pins);
 }
 
-pub fn unset(p: &msp430g2211::PORT_1_2, pins: Pins) {
-    clear_port_reg(&p.p1out, pins)
+// Set and clear pins with a single write to P1OUT, so that they all change
+// at the same instant. A pin shouldn't be in both masks.
+pub fn set_and_clear(p: &pac::PORT_1_2, set_pins: Pins, clear_pins: Pins) {
+    let (high, low) = out_levels(set_pins, clear_pins, INVERTED);
+
+    p.p1out.modify(|r, w| {
+        let pins = (Pins::from(r) | high) & !low;
+        w.p1out().bits(pins.bits())
+    });
 }
 
-pub fn mk_in(p: &msp430g2211::PORT_1_2, pins: Pins) {
+// The P1OUT bits to drive high and low to set and clear pins, given which
+// of them are inverted.
+const fn out_levels(set_pins: Pins, clear_pins: Pins, inverted: Pins) -> (Pins, Pins) {
+    let high = set_pins
+        .difference(inverted)
+        .union(clear_pins.intersection(inverted));
+    let low = clear_pins
+        .difference(inverted)
+        .union(set_pins.intersection(inverted));
+    (high, low)
+}
+
+// The levels the keyboard sees, from a read of P1IN.
+const fn levels(raw: Pins, inverted: Pins) -> Pins {
+    raw.symmetric_difference(inverted)
+}
+
+// For each combination of AT_DATA_INVERTED and AT_CLK_INVERTED: set one AT
+// line and clear the other, and read back what that drives.
+const _: () = {
+    const fn check(inverted: Pins) {
+        let (high, low) = out_levels(Pins::AT_CLK, Pins::AT_DATA, inverted);
+        let clk_inverted = inverted.contains(Pins::AT_CLK);
+        let data_inverted = inverted.contains(Pins::AT_DATA);
+
+        assert!(high.contains(Pins::AT_CLK) != clk_inverted);
+        assert!(low.contains(Pins::AT_CLK) == clk_inverted);
+        assert!(high.contains(Pins::AT_DATA) == data_inverted);
+        assert!(low.contains(Pins::AT_DATA) != data_inverted);
+        // Nothing else is touched.
+        assert!(high.union(low).bits() == Pins::AT_MASK.bits());
+        assert!(high.intersection(low).is_empty());
+
+        // P1IN reads high exactly where P1OUT drives high.
+        let seen = levels(high, inverted).intersection(Pins::AT_MASK);
+        assert!(seen.bits() == Pins::AT_CLK.bits());
+    }
+
+    check(Pins::empty());
+    check(Pins::AT_DATA);
+    check(Pins::AT_CLK);
+    check(Pins::AT_MASK);
+};
+
+pub fn mk_in(p: &pac::PORT_1_2, pins: Pins) {
     clear_port_reg(&p.p1dir, pins)
 }
 
+pub fn mk_out(p: &pac::PORT_1_2, pins: Pins) {
+    set_port_reg(&p.p1dir, pins)
+}
+
 // The following two functions are only meant to be used to test one pin at a time,
 // although multiple pins should work ("if all are set", "if all are unset").
-pub fn is_set(p: &msp430g2211::PORT_1_2, pins: Pins) -> bool {
-    Pins::from(&p.p1in.read()).contains(pins)
+pub fn is_set(p: &pac::PORT_1_2, pins: Pins) -> bool {
+    levels(Pins::from(&p.p1in.read()), INVERTED).contains(pins)
 }
 
-pub fn is_unset(p: &msp430g2211::PORT_1_2, pins: Pins) -> bool {
-    !Pins::from(&p.p1in.read()).intersects(pins)
+pub fn is_unset(p: &pac::PORT_1_2, pins: Pins) -> bool {
+    !levels(Pins::from(&p.p1in.read()), INVERTED).intersects(pins)
 }
 
-pub fn idle(p: &msp430g2211::PORT_1_2) {
+// Read the current level of some pins, e.g. to watch them for changes.
+#[cfg(feature = "kbd-powersave")]
+pub fn sample(p: &pac::PORT_1_2, pins: Pins) -> Pins {
+    levels(Pins::from(&p.p1in.read()), INVERTED) & pins
+}
+
+// Like is_unset, but the pins must read unset on every one of `samples`
+// consecutive reads.
+#[cfg(feature = "at-debounce")]
+pub fn is_unset_stable(p: &pac::PORT_1_2, pins: Pins, samples: u8) -> bool {
+    (0..samples).all(|_| is_unset(p, pins))
+}
+
+pub fn idle(p: &pac::PORT_1_2) {
     p.p1dir.write(|w| w.p1dir().bits(0x00));
     clear_port_reg(&p.p1ifg, Pins::AT_CLK);
-    set_port_reg(&p.p1ies, Pins::AT_CLK);
+    // Interrupt on the falling edge the keyboard drives.
+    if AT_CLK_INVERTED {
+        clear_port_reg(&p.p1ies, Pins::AT_CLK);
+    } else {
+        set_port_reg(&p.p1ies, Pins::AT_CLK);
+    }
     set_port_reg(&p.p1ie, Pins::AT_CLK);
 }
 
-pub fn disable_at_clk_int(p: &msp430g2211::PORT_1_2) {
+// The known safe electrical state: every line released to its pull-up, and
+// no pin interrupts enabled or pending. Unlike idle(), AT_CLK isn't armed
+// again. The AT and XT lines are left set in P1OUT, so making any of them an
+// output later won't pull it low by surprise.
+pub fn park(p: &pac::PORT_1_2) {
+    p.p1ie.write(|w| w.p1ie().bits(0x00));
+    set(p, Pins::AT_MASK | Pins::XT_MASK);
+    p.p1dir.write(|w| w.p1dir().bits(0x00));
+    p.p1ifg.write(|w| w.p1ifg().bits(0x00));
+}
+
+pub fn disable_at_clk_int(p: &pac::PORT_1_2) {
     clear_port_reg(&p.p1ie, Pins::AT_CLK);
 }
 
 // Spurious pin interrupts are undesireable, but should not cause memory
 // safety issues (data races) due to the various Cells.
-pub fn enable_at_clk_int(p: &msp430g2211::PORT_1_2) {
+pub fn enable_at_clk_int(p: &pac::PORT_1_2) {
     set_port_reg(&p.p1ie, Pins::AT_CLK);
 }
 
-pub fn clear_at_clk_int(p: &msp430g2211::PORT_1_2) {
+pub fn clear_at_clk_int(p: &pac::PORT_1_2) {
     clear_port_reg(&p.p1ifg, Pins::AT_CLK);
 }
 
-pub fn at_idle(p: &msp430g2211::PORT_1_2) {
+// Whether AT_CLK has an edge waiting, e.g. one that came while its interrupt
+// was disabled.
+#[cfg(feature = "strict-xt-timing")]
+pub fn at_clk_int_pending(p: &pac::PORT_1_2) -> bool {
+    Pins::from(&p.p1ifg.read()).contains(Pins::AT_CLK)
+}
+
+pub fn at_idle(p: &pac::PORT_1_2) {
     set(p, Pins::AT_CLK);
     set(p, Pins::AT_DATA);
     clear_port_reg(&p.p1dir, Pins::AT_MASK);
 }
 
-pub fn at_inhibit(p: &msp430g2211::PORT_1_2) {
+pub fn at_inhibit(p: &pac::PORT_1_2) {
     unset(p, Pins::AT_CLK);
     set(p, Pins::AT_DATA);
     set_port_reg(&p.p1dir, Pins::AT_MASK);
 }
 
-pub fn xt_out(p: &msp430g2211::PORT_1_2) {
+// Enable the internal pull-ups on some input pins, e.g. to read jumpers to
+// ground. Give them a moment before reading.
+#[cfg(feature = "loopback-test")]
+pub fn pull_up(p: &pac::PORT_1_2, pins: Pins) {
+    clear_port_reg(&p.p1dir, pins);
+    set_port_reg(&p.p1out, pins);
+    set_port_reg(&p.p1ren, pins);
+}
+
+// Drive both AT lines high ourselves, playing the keyboard. PORT1 still
+// sees the edges we make on AT_CLK.
+#[cfg(feature = "loopback-test")]
+pub fn at_drive(p: &pac::PORT_1_2) {
+    set(p, Pins::AT_MASK);
+    set_port_reg(&p.p1dir, Pins::AT_MASK);
+}
+
+pub fn xt_out(p: &pac::PORT_1_2) {
     set_port_reg(&p.p1out, Pins::XT_MASK);
     set_port_reg(&p.p1dir, Pins::XT_MASK);
 }
 
-pub fn xt_in(p: &msp430g2211::PORT_1_2) {
+pub fn xt_in(p: &pac::PORT_1_2) {
     set_port_reg(&p.p1out, Pins::XT_DATA);
     clear_port_reg(&p.p1dir, Pins::XT_MASK);
 }
@@ -0,0 +1,74 @@
+// The last few things the converter did, for post-mortem debugging. Compiled
+// out (log() does nothing) without the event-log feature.
+#[cfg(feature = "event-log")]
+use core::cell::RefCell;
+#[cfg(feature = "event-log")]
+use msp430::{critical_section as mspcs, interrupt::Mutex};
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum Event {
+    AtReceived = 1, // A frame came in from the keyboard.
+    BadFrame,
+    XtSent,
+    KeyboardReset,
+    HostReset,
+    FsmError,
+}
+
+#[cfg(feature = "event-log")]
+struct Ring {
+    next: u8,
+    events: [u8; 8],
+}
+
+#[cfg(feature = "event-log")]
+impl Ring {
+    // Same masking trick as KeycodeBuffer to elide bounds checks.
+    #[allow(clippy::as_conversions)]
+    fn put(&mut self, event: Event) {
+        if let Some(e) = self.events.get_mut(usize::from(self.next % 8)) {
+            *e = event as u8;
+            self.next = self.next.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(feature = "event-log")]
+static LOG: Mutex<RefCell<Ring>> = Mutex::new(RefCell::new(Ring {
+    next: 0,
+    events: [0; 8],
+}));
+
+#[cfg(feature = "event-log")]
+pub fn log(event: Event) {
+    mspcs::with(|cs| {
+        if let Ok(mut l) = LOG.borrow(cs).try_borrow_mut() {
+            l.put(event);
+        }
+    });
+}
+
+#[cfg(not(feature = "event-log"))]
+#[inline(always)]
+pub fn log(_event: Event) {}
+
+// Hand each logged event code to f, oldest first. Unused slots are skipped.
+#[cfg(feature = "event-log")]
+#[allow(dead_code)]
+pub fn dump<F: FnMut(u8)>(mut f: F) {
+    let (next, events) = mspcs::with(|cs| {
+        LOG.borrow(cs)
+            .try_borrow()
+            .map_or((0, [0; 8]), |l| (l.next, l.events))
+    });
+
+    for i in 0..8 {
+        if let Some(&e) = events.get(usize::from(next.wrapping_add(i) % 8)) {
+            if e != 0 {
+                f(e);
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+// Host mode: the converter is sending a byte to the keyboard, and PORT1 puts
+// the next bit of KEY_OUT on AT_DATA at each clock edge. Outside host mode
+// PORT1 leaves KEY_OUT alone, which is what lets the main loop load it in
+// several steps. Both live here so the order can't be got wrong: the main
+// loop only touches KEY_OUT with host mode off, and only turns host mode on
+// with a frame loaded.
+use crate::keybuffer::KeyOut;
+use core::cell::Cell;
+use msp430::interrupt::{CriticalSection, Mutex};
+use portable_atomic::{AtomicBool, Ordering};
+
+static HOST_MODE: AtomicBool = AtomicBool::new(false);
+static DEVICE_ACK: AtomicBool = AtomicBool::new(false);
+static KEY_OUT: Mutex<Cell<KeyOut>> = Mutex::new(Cell::new(KeyOut::new()));
+
+pub fn is_active() -> bool {
+    HOST_MODE.load(Ordering::SeqCst)
+}
+
+// Change KEY_OUT from the main loop. Refused in host mode, when it's
+// PORT1's.
+pub fn update_key_out<T, F>(cs: CriticalSection, f: F) -> Result<T, ()>
+where
+    F: FnOnce(&mut KeyOut) -> Result<T, ()>,
+{
+    if is_active() {
+        return Err(());
+    }
+
+    let cell = KEY_OUT.borrow(cs);
+    let mut key_out = cell.get();
+    let res = f(&mut key_out);
+    cell.set(key_out);
+    res
+}
+
+// Hand KEY_OUT to PORT1, once the lines are set up for it to clock the rest
+// of the frame out. Taking cs means PORT1 can't see host mode before the
+// last acknowledgement is forgotten.
+pub fn enter(cs: CriticalSection) -> Result<(), ()> {
+    if KEY_OUT.borrow(cs).get().is_empty() {
+        return Err(());
+    }
+
+    DEVICE_ACK.store(false, Ordering::SeqCst);
+    HOST_MODE.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+// Take KEY_OUT back from PORT1. Returns whether the keyboard acknowledged
+// the frame; if not, what's left of it is still in KEY_OUT.
+pub fn exit() -> bool {
+    HOST_MODE.store(false, Ordering::SeqCst);
+    DEVICE_ACK.load(Ordering::SeqCst)
+}
+
+pub fn acked() -> bool {
+    DEVICE_ACK.load(Ordering::SeqCst)
+}
+
+// For PORT1: the next bit to put on AT_DATA, and whether it's the last (the
+// stop bit), or None once the frame is out or outside host mode.
+pub fn next_bit(cs: CriticalSection) -> Option<(bool, bool)> {
+    if !is_active() {
+        return None;
+    }
+
+    let cell = KEY_OUT.borrow(cs);
+    let mut key_out = cell.get();
+    let bit = key_out.shift_out()?;
+    cell.set(key_out);
+    Some((bit, key_out.is_empty()))
+}
+
+// For PORT1: the keyboard pulled AT_DATA low after the stop bit.
+pub fn ack(cs: CriticalSection) {
+    if is_active() {
+        KEY_OUT.borrow(cs).set(KeyOut::new());
+        DEVICE_ACK.store(true, Ordering::SeqCst);
+    }
+}
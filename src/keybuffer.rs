@@ -1,62 +1,268 @@
+use crate::util;
+
 pub struct KeycodeBuffer {
     head: u8,
     tail: u8,
+    max_len: u8, // High-water mark, for sizing the buffer.
     contents: [u16; 16],
 }
 
 impl KeycodeBuffer {
+    // Every slot is usable: len() tells a full buffer (16) from an empty one
+    // (0), see below.
+    pub const CAPACITY: u8 = 16;
+
     pub const fn new() -> KeycodeBuffer {
         KeycodeBuffer {
             head: 0,
             tail: 0,
+            max_len: 0,
             contents: [0; 16],
         }
     }
 
-    pub fn flush(&mut self) {
+    pub const fn flush(&mut self) {
         self.tail = 0;
         self.head = 0;
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.head.wrapping_sub(self.tail) == 0
+    pub const fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // head and tail are free-running u8s, reduced mod 16 only to index
+    // contents. 256 is a multiple of 16, and put() keeps tail at most
+    // CAPACITY ahead of head, so their difference is always the number of
+    // keys queued, even after either one wraps.
+    pub const fn len(&self) -> u8 {
+        self.tail.wrapping_sub(self.head)
     }
 
-    pub fn put(&mut self, in_key: u16) -> Result<(), ()> {
+    #[allow(clippy::indexing_slicing, clippy::as_conversions)]
+    pub const fn put(&mut self, in_key: u16) -> Result<(), ()> {
         // if self.tail.wrapping_sub(self.head) >= 16 might be possible!
-        if self.tail.wrapping_sub(self.head) >= 15 {
+        if self.len() >= Self::CAPACITY {
             Err(())
         } else {
             /* The most space-efficient way to add/remove queue elements is to
             force the array access to be within bounds by ignoring the top bits
             (equivalent to "% power_of_two"). This will optimize out the bounds
-            check. */
-            if let Some(buf_ref) = self.contents.get_mut(usize::from(self.tail % 16)) {
-                *buf_ref = in_key;
-                self.tail = self.tail.wrapping_add(1);
-                Ok(())
-            } else {
-                Err(())
+            check, and unlike get_mut() works in a const fn. */
+            self.contents[(self.tail % 16) as usize] = in_key;
+            self.tail = self.tail.wrapping_add(1);
+            if self.len() > self.max_len {
+                self.max_len = self.len();
             }
+            Ok(())
         }
     }
 
-    pub fn take(&mut self) -> Option<u16> {
+    #[allow(clippy::indexing_slicing, clippy::as_conversions)]
+    pub const fn take(&mut self) -> Option<u16> {
         if self.is_empty() {
             None
         } else {
             // Same logic applies as with tail.
-            let out_key = self.contents.get(usize::from(self.head % 16));
+            let out_key = self.contents[(self.head % 16) as usize];
+            self.head = self.head.wrapping_add(1);
+            Some(out_key)
+        }
+    }
 
-            if out_key.is_some() {
-                self.head = self.head.wrapping_add(1);
-            }
+    // Most keys ever queued at once since creation or the last
+    // reset_high_water_mark(). Survives flush().
+    #[allow(dead_code)]
+    pub const fn high_water_mark(&self) -> u8 {
+        self.max_len
+    }
+
+    #[allow(dead_code)]
+    pub fn reset_high_water_mark(&mut self) {
+        self.max_len = 0;
+    }
+
+    // Empty the buffer, but hand back the most recently queued key (the one
+    // just behind tail), so that e.g. a mode switch can keep the key which
+    // triggered it. All older keys are discarded.
+    #[allow(dead_code)]
+    #[allow(clippy::indexing_slicing, clippy::as_conversions)]
+    pub const fn drain_last(&mut self) -> Option<u16> {
+        let last = if self.is_empty() {
+            None
+        } else {
+            Some(self.contents[(self.tail.wrapping_sub(1) % 16) as usize])
+        };
+
+        self.flush();
+        last
+    }
 
-            out_key.copied()
+    // Hand back everything queued, as a buffer to take() from oldest first,
+    // and leave this one empty. Call it with the buffer borrowed (i.e. in the
+    // critical section) for a snapshot the ISR can't add to halfway, then
+    // work through the snapshot after letting go. The high-water mark stays
+    // here. The snapshot is as big as the buffer, which is a lot of stack on
+    // this part.
+    #[cfg_attr(not(feature = "boot-replay"), allow(dead_code))]
+    pub const fn take_all(&mut self) -> KeycodeBuffer {
+        let all = KeycodeBuffer {
+            head: self.head,
+            tail: self.tail,
+            max_len: 0,
+            contents: self.contents,
+        };
+
+        self.head = self.tail;
+        all
+    }
+
+    // Discard all but the keep most recently queued keys, oldest first.
+    // keep = 0 empties the buffer; keep at or above the number of queued
+    // keys leaves it alone.
+    #[allow(dead_code)]
+    pub const fn flush_older_than(&mut self, keep: u8) {
+        if self.tail.wrapping_sub(self.head) > keep {
+            self.head = self.tail.wrapping_sub(keep);
         }
     }
 }
 
+// head and tail are free-running and only reduced mod 16 on access, so
+// these include buffers where tail has wrapped past 255 and head hasn't.
+const _: () = {
+    const fn kept(head: u8, tail: u8, keep: u8) -> u8 {
+        let mut b = KeycodeBuffer {
+            head,
+            tail,
+            max_len: 0,
+            contents: [0; 16],
+        };
+
+        b.flush_older_than(keep);
+        assert!(b.tail == tail); // The newest keys stay put.
+        b.tail.wrapping_sub(b.head)
+    }
+
+    assert!(kept(2, 7, 3) == 3);
+    assert!(kept(2, 7, 5) == 5);
+    assert!(kept(2, 7, 9) == 5); // keep > len
+    assert!(kept(2, 7, 0) == 0); // Full flush.
+    assert!(kept(0, 0, 4) == 0);
+    assert!(kept(250, 4, 3) == 3); // Wraparound.
+    assert!(kept(250, 4, 0) == 0);
+    assert!(kept(250, 4, 12) == 10);
+};
+
+// take_all keeps the queue's order, including across the end of contents and
+// the wrap of head and tail themselves.
+#[allow(clippy::indexing_slicing, clippy::as_conversions)]
+const _: () = {
+    const fn check(head: u8, len: u8) {
+        let mut b = KeycodeBuffer {
+            head,
+            tail: head.wrapping_add(len),
+            max_len: len,
+            contents: [0; 16],
+        };
+
+        let mut i = 0;
+        while i < len {
+            b.contents[(head.wrapping_add(i) % 16) as usize] = 0x100 + i as u16;
+            i += 1;
+        }
+
+        let all = b.take_all();
+        assert!(b.is_empty());
+        assert!(b.max_len == len);
+        assert!(all.len() == len);
+
+        let mut i = 0;
+        while i < len {
+            assert!(all.contents[(all.head.wrapping_add(i) % 16) as usize] == 0x100 + i as u16);
+            i += 1;
+        }
+    }
+
+    check(0, 0);
+    check(0, 5);
+    check(14, 5); // contents wraps.
+    check(250, 10); // tail wraps past 255.
+    check(3, KeycodeBuffer::CAPACITY);
+};
+
+// drain_last hands back the newest key, wherever it sits in contents, and
+// leaves the buffer empty.
+#[allow(clippy::indexing_slicing, clippy::as_conversions)]
+const _: () = {
+    const fn last(head: u8, len: u8) -> Option<u16> {
+        let mut b = KeycodeBuffer {
+            head,
+            tail: head.wrapping_add(len),
+            max_len: len,
+            contents: [0; 16],
+        };
+
+        let mut i = 0;
+        while i < len {
+            b.contents[(head.wrapping_add(i) % 16) as usize] = 0x100 + i as u16;
+            i += 1;
+        }
+
+        let last = b.drain_last();
+        assert!(b.is_empty());
+        last
+    }
+
+    assert!(last(0, 0).is_none());
+    assert!(last(9, 0).is_none());
+    assert!(matches!(last(0, 1), Some(0x100)));
+    assert!(matches!(last(12, 7), Some(0x106))); // contents wraps.
+    assert!(matches!(last(250, 10), Some(0x109))); // tail wraps past 255.
+    assert!(matches!(last(5, KeycodeBuffer::CAPACITY), Some(0x10f))); // Full.
+};
+
+// Walk head and tail with put() and take() through five or so wraps of
+// both, filling the buffer and draining it to a different level each time.
+// Keys must come out in the order they went in, and len and is_empty must
+// match a separately kept count throughout.
+const _: () = {
+    let mut b = KeycodeBuffer::new();
+    let mut count: u8 = 0;
+    let mut cycle: u8 = 0;
+    let mut next_in: u16 = 0;
+    let mut next_out: u16 = 0;
+
+    while cycle < 100 {
+        while count < KeycodeBuffer::CAPACITY {
+            assert!(b.put(next_in).is_ok());
+            next_in += 1;
+            count += 1;
+            assert!(b.len() == count && !b.is_empty());
+        }
+
+        assert!(b.put(next_in).is_err()); // Full.
+        assert!(b.high_water_mark() == KeycodeBuffer::CAPACITY);
+
+        while count > cycle % 5 {
+            assert!(matches!(b.take(), Some(k) if k == next_out));
+            next_out += 1;
+            count -= 1;
+            assert!(b.len() == count && b.is_empty() == (count == 0));
+        }
+
+        cycle += 1;
+    }
+
+    while count > 0 {
+        assert!(matches!(b.take(), Some(k) if k == next_out));
+        next_out += 1;
+        count -= 1;
+    }
+
+    assert!(b.take().is_none());
+    assert!(next_out == next_in);
+};
+
 #[derive(Clone, Copy)]
 pub struct KeyIn {
     pos: u8,
@@ -64,6 +270,12 @@ pub struct KeyIn {
 }
 
 impl KeyIn {
+    // Standard AT frames are a start bit, 8 data bits, parity, and stop.
+    // Some early keyboards differ in what follows the data; change this to
+    // match. Data always immediately follows the start bit.
+    pub const FRAME_BITS: u8 = 11;
+    const TRAILING_BITS: u8 = Self::FRAME_BITS - 9;
+
     pub const fn new() -> KeyIn {
         KeyIn {
             pos: 0,
@@ -71,8 +283,13 @@ impl KeyIn {
         }
     }
 
+    // No bits of the next frame yet.
+    pub const fn is_empty(self) -> bool {
+        self.pos == 0
+    }
+
     fn is_full(self) -> bool {
-        self.pos >= 11
+        self.pos >= Self::FRAME_BITS
     }
 
     pub fn clear(&mut self) {
@@ -82,6 +299,8 @@ impl KeyIn {
 
     pub fn shift_in(&mut self, bit: bool) -> Result<(), ()> {
         // TODO: A nonzero start value (when self.pos == 0) is a runtime invariant violation.
+        // Once full, refuse to shift until take() drains the frame. Otherwise
+        // a spurious extra clock would shift the start bit out and corrupt it.
         if self.is_full() {
             Err(())
         } else {
@@ -96,42 +315,198 @@ impl KeyIn {
         }
     }
 
+    // Draining a full frame also resets KeyIn for the next one.
     pub fn take(&mut self) -> Option<u16> {
         if self.is_full() {
-            self.pos = 0;
-            Some(self.contents)
+            let frame = self.contents;
+            self.clear();
+            Some(frame)
         } else {
             None
         }
     }
 }
 
+// The fields of a frame as KeyIn holds it. Frames are shifted in MSB-first,
+// so the start bit ends up on top, with the data below it in reverse order,
+// followed by the trailing bits: parity (if there's room), then stop bits.
+#[derive(Clone, Copy)]
+pub struct AtFrame(u16);
+
+impl AtFrame {
+    const START: u16 = 1 << (KeyIn::FRAME_BITS - 1);
+    const PARITY: u16 = if KeyIn::TRAILING_BITS >= 2 {
+        1 << (KeyIn::TRAILING_BITS - 1)
+    } else {
+        0
+    };
+    const STOP: u16 = ((1 << KeyIn::TRAILING_BITS) - 1) & !Self::PARITY;
+
+    pub const fn new(frame: u16) -> AtFrame {
+        AtFrame(frame)
+    }
+
+    // Should be low.
+    #[allow(dead_code)]
+    pub const fn start(self) -> bool {
+        self.0 & Self::START != 0
+    }
+
+    #[allow(clippy::as_conversions)]
+    pub const fn data(self) -> u8 {
+        // Truncation drops the start bit.
+        ((self.0 >> KeyIn::TRAILING_BITS) as u8).reverse_bits()
+    }
+
+    // Always false in a frame without room for parity.
+    #[allow(dead_code)]
+    pub const fn parity(self) -> bool {
+        self.0 & Self::PARITY != 0
+    }
+
+    // Whether every stop bit is high, as it should be.
+    #[allow(dead_code)]
+    pub const fn stop(self) -> bool {
+        self.0 & Self::STOP == Self::STOP
+    }
+}
+
+// Every byte, in a well-formed frame and with each field flipped in turn.
+const _: () = {
+    let mut byte: u8 = 0;
+
+    loop {
+        let parity: u16 = if util::compute_parity(byte) { 0b10 } else { 0 };
+        #[allow(clippy::as_conversions)]
+        let good = (byte.reverse_bits() as u16) << 2 | parity | 1;
+
+        let f = AtFrame::new(good);
+        assert!(!f.start() && f.data() == byte && f.stop());
+        assert!(f.parity() == util::compute_parity(byte));
+
+        let f = AtFrame::new(good | 1 << 10);
+        assert!(f.start() && f.data() == byte);
+        let f = AtFrame::new(good ^ 0b10);
+        assert!(f.parity() != util::compute_parity(byte) && f.data() == byte);
+        let f = AtFrame::new(good & !1);
+        assert!(!f.stop() && f.data() == byte);
+
+        if byte == u8::MAX {
+            break;
+        }
+        byte += 1;
+    }
+};
+
+pub fn decode_at_frame(frame: u16) -> u8 {
+    AtFrame::new(frame).data()
+}
+
+#[derive(Clone, Copy)]
+pub enum FrameError {
+    Start,
+    Parity,
+    Stop,
+}
+
+// decode_at_frame, but reject frames with a bad parity bit, and with
+// strict-framing, a bad start or stop bit. A good frame is exactly what the
+// keyboard would send for its data.
+pub fn check_at_frame(frame: u16) -> Result<u8, FrameError> {
+    let byte = decode_at_frame(frame);
+
+    match frame_error(frame, encode_at_frame(byte)) {
+        Some(e) => Err(e),
+        None => Ok(byte),
+    }
+}
+
+// How frame differs from good, the frame its data should have come in. A
+// bad stop bit is the likeliest sign of a frame that lost sync, so it's
+// reported over a bad parity bit.
+const fn frame_error(frame: u16, good: u16) -> Option<FrameError> {
+    const STRICT: bool = cfg!(feature = "strict-framing");
+
+    let diff = frame ^ good;
+
+    if STRICT && diff & AtFrame::STOP != 0 {
+        Some(FrameError::Stop)
+    } else if STRICT && diff & AtFrame::START != 0 {
+        Some(FrameError::Start)
+    } else if diff & AtFrame::PARITY != 0 {
+        Some(FrameError::Parity)
+    } else {
+        None
+    }
+}
+
+// 0x12 arrives as 0 (start), 0100_1000 (data, LSB first), 1 (parity), 1
+// (stop).
+const _: () = {
+    const STRICT: bool = cfg!(feature = "strict-framing");
+    let good: u16 = 0b0001_0010_0011;
+
+    assert!(frame_error(good, good).is_none());
+
+    let e = frame_error(good ^ 0b10, good);
+    assert!(matches!(e, Some(FrameError::Parity)));
+
+    // A zeroed stop bit.
+    let e = frame_error(good & !1, good);
+    assert!(STRICT == matches!(e, Some(FrameError::Stop)));
+
+    let e = frame_error(good | 1 << 10, good);
+    assert!(STRICT == matches!(e, Some(FrameError::Start)));
+
+    // A zeroed stop bit with a bad parity bit is reported as a stop error.
+    let e = frame_error(good & !0b11, good);
+    assert!(STRICT == matches!(e, Some(FrameError::Stop)));
+};
+
+// The inverse of decode_at_frame: the frame KeyIn holds after a keyboard
+// sends byte. The trailing bits are parity (if there's room), then stop bits.
+pub fn encode_at_frame(byte: u8) -> u16 {
+    let data = u16::from(byte.reverse_bits()) << KeyIn::TRAILING_BITS;
+    let trailing: u16 = (1 << KeyIn::TRAILING_BITS) - 1;
+    let parity_clear: u16 = if KeyIn::TRAILING_BITS >= 2 && !util::compute_parity(byte) {
+        1 << (KeyIn::TRAILING_BITS - 1)
+    } else {
+        0
+    };
+
+    data | (trailing & !parity_clear)
+}
+
 #[derive(Clone, Copy)]
 pub struct KeyOut {
     pos: u8,
     contents: u16,
 }
 
+// A whole host-to-keyboard frame. The start bit is shifted out first, by
+// send_byte_to_at_keyboard as part of the request-to-send; PORT1 shifts out
+// the rest as the keyboard clocks them.
 impl KeyOut {
+    // Start, data 0-7, parity, and stop.
+    pub const FRAME_BITS: u8 = 11;
+
     pub const fn new() -> KeyOut {
         KeyOut {
-            pos: 10,
+            pos: Self::FRAME_BITS,
             contents: 0,
         }
     }
 
-    pub fn is_empty(self) -> bool {
-        self.pos > 9 // Data 0-7, Parity, and Stop. Start bit has to be handled specially b/c
-                     // it's part of keyboard negotiation.
+    pub const fn is_empty(self) -> bool {
+        self.pos >= Self::FRAME_BITS
     }
 
-    pub fn clear(&mut self) {
-        self.pos = 10;
+    pub const fn clear(&mut self) {
+        self.pos = Self::FRAME_BITS;
         self.contents = 0;
     }
 
-    pub fn shift_out(&mut self) -> Option<bool> {
-        // TODO: A nonzero start value (when self.pos == 0) is a runtime invariant violation.
+    pub const fn shift_out(&mut self) -> Option<bool> {
         if self.is_empty() {
             None
         } else {
@@ -142,16 +517,73 @@ impl KeyOut {
         }
     }
 
-    pub fn put(&mut self, byte: u8) -> Result<(), ()> {
+    pub const fn put(&mut self, byte: u8) -> Result<(), ()> {
         if !self.is_empty() {
             return Err(());
         }
 
-        let num_ones = byte.count_ones();
-        let stop_bit: u16 = 1 << 9;
-        let parity_bit: u16 = if num_ones % 2 == 0 { 1 << 8 } else { 0 };
-        self.contents = u16::from(byte) | parity_bit | stop_bit;
+        self.contents = Self::frame(byte);
         self.pos = 0;
         Ok(())
     }
+
+    // Host-to-keyboard frames go out LSB first: start (0), data 0-7, then
+    // parity, then stop.
+    #[allow(clippy::as_conversions)]
+    const fn frame(byte: u8) -> u16 {
+        let stop_bit: u16 = 1 << 10;
+        let parity_bit: u16 = if util::compute_parity(byte) {
+            1 << 9
+        } else {
+            0
+        };
+
+        (byte as u16) << 1 | parity_bit | stop_bit
+    }
 }
+
+// Shift a whole frame out and check the bits leave in the order the
+// keyboard expects. 0x12 has an even number of ones, so parity is set.
+const _: () = {
+    // Stop and parity, then data MSB-first, then start; read right to left.
+    let mut expected: u16 = 0b0110_0010_0100;
+    let mut k = KeyOut::new();
+
+    assert!(k.put(0x12).is_ok());
+    assert!(k.put(0x34).is_err());
+
+    let mut bits = 0;
+    while let Some(bit) = k.shift_out() {
+        assert!(bit == (expected & 0x01 == 1));
+        expected >>= 1;
+        bits += 1;
+    }
+
+    assert!(bits == 11);
+    assert!(k.is_empty());
+};
+
+// The frame is only done once the stop bit has gone: not one shift before,
+// and with nothing left after. Likewise straight after clear.
+const _: () = {
+    let mut k = KeyOut::new();
+    assert!(k.put(0x00).is_ok());
+
+    let mut shifts = 0;
+    while shifts < KeyOut::FRAME_BITS - 1 {
+        assert!(!k.is_empty());
+        assert!(k.shift_out().is_some());
+        shifts += 1;
+    }
+
+    assert!(!k.is_empty());
+    assert!(matches!(k.shift_out(), Some(true))); // Stop.
+    assert!(k.is_empty());
+    assert!(k.shift_out().is_none());
+
+    assert!(k.put(0x00).is_ok());
+    assert!(matches!(k.shift_out(), Some(false))); // Start.
+    k.clear();
+    assert!(k.is_empty());
+    assert!(k.shift_out().is_none());
+};
@@ -1,16 +1,29 @@
+#[cfg(feature = "leds")]
 use bitflags::bitflags;
+use core::convert::TryFrom;
 
 mod keymap {
+    // The Windows and Menu keys (AT E0 1F, E0 27, E0 2F) only exist with an
+    // E0 prefix, so their slots are otherwise unused. Old XT software may not
+    // expect them.
+    #[cfg(feature = "forward-gui-keys")]
+    const GUI_KEYS: [u8; 3] = [0x5B, 0x5C, 0x5D];
+    #[cfg(not(feature = "forward-gui-keys"))]
+    const GUI_KEYS: [u8; 3] = [0x00; 3];
+    const LGUI: u8 = GUI_KEYS[0];
+    const RGUI: u8 = GUI_KEYS[1];
+    const MENU: u8 = GUI_KEYS[2];
+
     static KEYCODE_LUT: [u8; 132] =
         // 0    1    2    3    4    5    6    7    8    9    A    B    C    D    E    F
         [
             0x00, 0x43, 0x00, 0x3F, 0x3D, 0x3B, 0x3C, 0x58, 0x00, 0x44, 0x42, 0x40, 0x3E, 0x0F,
             0x29, 0x00, 0x00, 0x38, 0x2A, 0x00, 0x1D, 0x10, 0x02, 0x00, 0x00, 0x00, 0x2C, 0x1F,
-            0x1E, 0x11, 0x03, 0x00, 0x00, 0x2E, 0x2D, 0x20, 0x12, 0x05, 0x04, 0x00, 0x00, 0x39,
-            0x2F, 0x21, 0x14, 0x13, 0x06, 0x00, 0x00, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x00,
+            0x1E, 0x11, 0x03, LGUI, 0x00, 0x2E, 0x2D, 0x20, 0x12, 0x05, 0x04, RGUI, 0x00, 0x39,
+            0x2F, 0x21, 0x14, 0x13, 0x06, MENU, 0x00, 0x31, 0x30, 0x23, 0x22, 0x15, 0x07, 0x00,
             0x00, 0x00, 0x32, 0x24, 0x16, 0x08, 0x09, 0x00, 0x00, 0x33, 0x25, 0x17, 0x18, 0x0B,
             0x0A, 0x00, 0x00, 0x34, 0x35, 0x26, 0x27, 0x19, 0x0C, 0x00, 0x00, 0x00, 0x28, 0x00,
-            0x1A, 0x0D, 0x00, 0x00, 0x3A, 0x36, 0x1C, 0x1B, 0x00, 0x2B, 0x00, 0x00, 0x00, 0x00,
+            0x1A, 0x0D, 0x00, 0x00, 0x3A, 0x36, 0x1C, 0x1B, 0x00, 0x2B, 0x00, 0x00, 0x00, 0x56,
             0x00, 0x00, 0x00, 0x00, 0x0E, 0x00, 0x00, 0x4F, 0x00, 0x4B, 0x47, 0x00, 0x00, 0x00,
             0x52, 0x53, 0x50, 0x4C, 0x4D, 0x48, 0x01, 0x45, 0x57, 0x4E, 0x51, 0x4A, 0x37, 0x49,
             0x46, 0x00, 0x00, 0x00, 0x00, 0x41,
@@ -19,13 +32,70 @@ mod keymap {
     pub fn to_xt(at_in: u8) -> Option<u8> {
         KEYCODE_LUT.get(usize::from(at_in)).copied()
     }
+
+    // The published set 2 to set 1 mapping for every non-extended key of a
+    // 102-key keyboard. Typos in KEYCODE_LUT are easy to make and hard to
+    // spot, so it's checked against this at compile time: listed codes must
+    // match, and every other slot (save the GUI keys) must be 0x00.
+    #[rustfmt::skip]
+    const REFERENCE: &[(u8, u8)] = &[
+        (0x01, 0x43), (0x03, 0x3F), (0x04, 0x3D), (0x05, 0x3B), (0x06, 0x3C), // F9, F5, F3, F1, F2
+        (0x07, 0x58), (0x09, 0x44), (0x0A, 0x42), (0x0B, 0x40), (0x0C, 0x3E), // F12, F10, F8, F6, F4
+        (0x0D, 0x0F), (0x0E, 0x29), (0x11, 0x38), (0x12, 0x2A), (0x14, 0x1D), // Tab, `, LAlt, LShift, LCtrl
+        (0x15, 0x10), (0x16, 0x02), (0x1A, 0x2C), (0x1B, 0x1F), (0x1C, 0x1E), // Q, 1, Z, S, A
+        (0x1D, 0x11), (0x1E, 0x03), (0x21, 0x2E), (0x22, 0x2D), (0x23, 0x20), // W, 2, C, X, D
+        (0x24, 0x12), (0x25, 0x05), (0x26, 0x04), (0x29, 0x39), (0x2A, 0x2F), // E, 4, 3, Space, V
+        (0x2B, 0x21), (0x2C, 0x14), (0x2D, 0x13), (0x2E, 0x06), (0x31, 0x31), // F, T, R, 5, N
+        (0x32, 0x30), (0x33, 0x23), (0x34, 0x22), (0x35, 0x15), (0x36, 0x07), // B, H, G, Y, 6
+        (0x3A, 0x32), (0x3B, 0x24), (0x3C, 0x16), (0x3D, 0x08), (0x3E, 0x09), // M, J, U, 7, 8
+        (0x41, 0x33), (0x42, 0x25), (0x43, 0x17), (0x44, 0x18), (0x45, 0x0B), // ,, K, I, O, 0
+        (0x46, 0x0A), (0x49, 0x34), (0x4A, 0x35), (0x4B, 0x26), (0x4C, 0x27), // 9, ., /, L, ;
+        (0x4D, 0x19), (0x4E, 0x0C), (0x52, 0x28), (0x54, 0x1A), (0x55, 0x0D), // P, -, ', [, =
+        (0x58, 0x3A), (0x59, 0x36), (0x5A, 0x1C), (0x5B, 0x1B), (0x5D, 0x2B), // Caps, RShift, Enter, ], \
+        (0x61, 0x56), (0x66, 0x0E), (0x69, 0x4F), (0x6B, 0x4B), (0x6C, 0x47), // ISO \, Bksp, KP1, KP4, KP7
+        (0x70, 0x52), (0x71, 0x53), (0x72, 0x50), (0x73, 0x4C), (0x74, 0x4D), // KP0, KP., KP2, KP5, KP6
+        (0x75, 0x48), (0x76, 0x01), (0x77, 0x45), (0x78, 0x57), (0x79, 0x4E), // KP8, Esc, Num, F11, KP+
+        (0x7A, 0x51), (0x7B, 0x4A), (0x7C, 0x37), (0x7D, 0x49), (0x7E, 0x46), // KP3, KP-, KP*, KP9, Scroll
+        (0x83, 0x41), // F7
+    ];
+
+    #[allow(clippy::indexing_slicing, clippy::as_conversions)]
+    const _: () = {
+        let mut at = 0;
+
+        while at < KEYCODE_LUT.len() {
+            let mut expected = match at {
+                0x1F => LGUI,
+                0x27 => RGUI,
+                0x2F => MENU,
+                _ => 0x00,
+            };
+
+            let mut i = 0;
+            while i < REFERENCE.len() {
+                if REFERENCE[i].0 as usize == at {
+                    expected = REFERENCE[i].1;
+                }
+                i += 1;
+            }
+
+            assert!(KEYCODE_LUT[at] == expected);
+            at += 1;
+        }
+    };
 }
 
 pub enum Cmd {
     WaitForKey,
     ClearBuffer, // If Reset Occurs.
+    #[cfg(feature = "leds")]
     ToggleLed(LedMask),
     SendXtKey(u8),
+    Idle,           // No key pending; run periodic maintenance.
+    ReinitKeyboard, // If a second reset quickly follows the first.
+    Magic(MagicAction),
+    #[cfg(feature = "prefer-keyboard-set1")]
+    SelectSet1, // Once the keyboard is on its power-on defaults.
 }
 
 impl Cmd {
@@ -34,11 +104,95 @@ impl Cmd {
 
     // AT commands
     pub const SET_LEDS: u8 = 0xed;
-    #[allow(dead_code)]
     pub const ECHO: u8 = 0xee;
+    pub const SCANCODE_SET: u8 = 0xf0;
+    pub const READ_ID: u8 = 0xf2;
+    pub const ENABLE: u8 = 0xf4;
+    #[cfg_attr(not(feature = "kbd-powersave"), allow(dead_code))]
+    pub const DISABLE: u8 = 0xf5;
+    pub const RESEND: u8 = 0xfe;
     pub const RESET: u8 = 0xff;
+
+    // AT reply; a keyboard asks for a resend with RESEND.
+    pub const ACK: u8 = 0xfa;
+
+    // The byte the main loop puts on the wire first for this command, if any.
+    #[allow(dead_code)]
+    pub fn as_byte(&self) -> Option<u8> {
+        match *self {
+            Cmd::SendXtKey(k) => Some(k),
+            #[cfg(feature = "leds")]
+            Cmd::ToggleLed(_) => Some(Self::SET_LEDS),
+            Cmd::ReinitKeyboard => Some(Self::SCANCODE_SET),
+            #[cfg(feature = "prefer-keyboard-set1")]
+            Cmd::SelectSet1 => Some(Self::SCANCODE_SET),
+            Cmd::WaitForKey | Cmd::ClearBuffer | Cmd::Idle | Cmd::Magic(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "leds")]
+impl From<LedMask> for Cmd {
+    fn from(mask: LedMask) -> Self {
+        Cmd::ToggleLed(mask)
+    }
+}
+
+// The command bytes we send over either port, by name.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CmdByte {
+    SelfTestPassed,
+    SetLeds,
+    Echo,
+    ScancodeSet,
+    Enable,
+    Disable,
+    Resend,
+    Reset,
+}
+
+impl CmdByte {
+    #[allow(dead_code)]
+    pub fn as_byte(self) -> u8 {
+        match self {
+            CmdByte::SelfTestPassed => Cmd::SELF_TEST_PASSED,
+            CmdByte::SetLeds => Cmd::SET_LEDS,
+            CmdByte::Echo => Cmd::ECHO,
+            CmdByte::ScancodeSet => Cmd::SCANCODE_SET,
+            CmdByte::Enable => Cmd::ENABLE,
+            CmdByte::Disable => Cmd::DISABLE,
+            CmdByte::Resend => Cmd::RESEND,
+            CmdByte::Reset => Cmd::RESET,
+        }
+    }
+}
+
+impl From<CmdByte> for u8 {
+    fn from(c: CmdByte) -> Self {
+        c.as_byte()
+    }
+}
+
+impl TryFrom<u8> for CmdByte {
+    type Error = ();
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            Cmd::SELF_TEST_PASSED => Ok(CmdByte::SelfTestPassed),
+            Cmd::SET_LEDS => Ok(CmdByte::SetLeds),
+            Cmd::ECHO => Ok(CmdByte::Echo),
+            Cmd::SCANCODE_SET => Ok(CmdByte::ScancodeSet),
+            Cmd::ENABLE => Ok(CmdByte::Enable),
+            Cmd::DISABLE => Ok(CmdByte::Disable),
+            Cmd::RESEND => Ok(CmdByte::Resend),
+            Cmd::RESET => Ok(CmdByte::Reset),
+            _ => Err(()),
+        }
+    }
 }
 
+#[cfg(feature = "leds")]
 bitflags! {
     #[derive(Default, Clone, Copy)]
     pub struct LedMask: u8 {
@@ -48,14 +202,177 @@ bitflags! {
     }
 }
 
+#[cfg(feature = "leds")]
+impl LedMask {
+    // XT make codes of the lock keys.
+    const XT_CAPS: u8 = 0x3a;
+    const XT_NUM: u8 = 0x45;
+    const XT_SCROLL: u8 = 0x46;
+
+    // Flip the LED belonging to an XT lock-key make code. Returns whether
+    // xt_code was a lock key at all.
+    #[allow(dead_code)]
+    pub fn toggle_for_scancode(&mut self, xt_code: u8) -> bool {
+        let led = match xt_code {
+            Self::XT_CAPS => LedMask::CAPS,
+            Self::XT_NUM => LedMask::NUM,
+            Self::XT_SCROLL => LedMask::SCROLL,
+            _ => return false,
+        };
+
+        self.toggle(led);
+        true
+    }
+}
+
+// Things the user can ask of the converter itself, without a host, by
+// tapping a key several times in quick succession.
+#[derive(Clone, Copy)]
+pub enum MagicAction {
+    ReinitKeyboard,
+    NextXtProfile, // Switch to the next XT timing profile.
+    #[cfg(feature = "macros")]
+    PlayMacro,
+}
+
+// Key (AT make code), number of taps, and what they do. Taps must all land
+// within Fsm::MAGIC_WINDOW_TICKS idle ticks of the first.
+#[cfg(not(feature = "macros"))]
+const MAGIC_CHORDS: [(u8, u8, MagicAction); 2] = [
+    (Fsm::SCROLL, 4, MagicAction::ReinitKeyboard),
+    (Fsm::NUM, 4, MagicAction::NextXtProfile),
+];
+#[cfg(feature = "macros")]
+const MAGIC_CHORDS: [(u8, u8, MagicAction); 3] = [
+    (Fsm::SCROLL, 4, MagicAction::ReinitKeyboard),
+    (Fsm::NUM, 4, MagicAction::NextXtProfile),
+    (Fsm::CAPS, 4, MagicAction::PlayMacro),
+];
+
+// The taps reach the host as well, so a chord on a lock key needs an even
+// number of them to leave the host's lock state (and LED) as it was.
+#[allow(clippy::indexing_slicing)]
+const _: () = {
+    let mut i = 0;
+
+    while i < MAGIC_CHORDS.len() {
+        let (key, taps, _) = MAGIC_CHORDS[i];
+        let lock = key == Fsm::SCROLL || key == Fsm::CAPS || key == Fsm::NUM;
+        assert!(!lock || taps % 2 == 0);
+        i += 1;
+    }
+};
+
+// Counts consecutive taps of the same key. Taps are counted on release, as
+// typematic repeats would otherwise look like extra presses.
+struct Chord {
+    key: u8,
+    taps: u8,
+    ticks: u8,
+}
+
+impl Chord {
+    const fn new() -> Chord {
+        Chord {
+            key: 0,
+            taps: 0,
+            ticks: 0,
+        }
+    }
+
+    // Returns the action a release of key completes, if any.
+    fn release(&mut self, key: u8) -> Option<MagicAction> {
+        if key != self.key || self.ticks > Fsm::MAGIC_WINDOW_TICKS {
+            self.key = key;
+            self.taps = 0;
+            self.ticks = 0;
+        }
+
+        self.taps = self.taps.saturating_add(1);
+
+        let action = MAGIC_CHORDS
+            .iter()
+            .find(|&&(k, taps, _)| k == key && taps == self.taps)
+            .map(|&(_, _, a)| a);
+
+        if action.is_some() {
+            self.taps = 0;
+        }
+
+        action
+    }
+
+    fn tick(&mut self) {
+        self.ticks = self.ticks.saturating_add(1);
+    }
+}
+
+// XT keys the firmware wants to send of its own accord, waiting for the FSM
+// to be between keys. Kept tiny; there's little RAM to spare.
+struct XtQueue {
+    keys: [u8; 4],
+    head: u8,
+    len: u8,
+}
+
+impl XtQueue {
+    const fn new() -> XtQueue {
+        XtQueue {
+            keys: [0; 4],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, key: u8) -> Result<(), ()> {
+        if self.len >= 4 {
+            return Err(());
+        }
+
+        let slot = self
+            .keys
+            .get_mut(usize::from(self.head.wrapping_add(self.len) % 4))
+            .ok_or(())?;
+        *slot = key;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let key = self.keys.get(usize::from(self.head % 4)).copied();
+        self.head = self.head.wrapping_add(1);
+        self.len -= 1;
+        key
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum FsmError {
+    UnexpectedReply, // The reply made no sense in the current state.
+    UnknownScancode,
+}
+
 pub enum ProcReply {
     // JustInitialized,
     NothingToDo,
     GrabbedKey(u8),
     SentKey(u8),
     ClearedBuffer,
+    #[cfg(feature = "leds")]
     LedToggled(LedMask),
     KeyboardReset,
+    KeyboardReinitialized,
+    IdleTick,
+    // An idle tick, but the keyboard hasn't been answering commands.
+    KeyboardAbsent,
+    MagicDone,
+    // Whether the keyboard reads back set 1.
+    #[cfg(feature = "prefer-keyboard-set1")]
+    Set1Selected(bool),
     //SentEcho,
 }
 
@@ -65,30 +382,106 @@ impl ProcReply {
     }
 }
 
+// One bit per XT code, with extended (E0-prefixed) keys in the upper half.
+#[cfg(feature = "collapse-repeats")]
+#[derive(Default)]
+struct KeySet([u8; 32]);
+
+#[cfg(feature = "collapse-repeats")]
+impl KeySet {
+    const fn index(xt: u8, extended: bool) -> u8 {
+        (xt & 0x7f) | if extended { 0x80 } else { 0 }
+    }
+
+    // Returns whether the key was newly inserted.
+    fn insert(&mut self, idx: u8) -> bool {
+        let mask = 1 << (idx % 8);
+        match self.0.get_mut(usize::from(idx / 8)) {
+            Some(b) if *b & mask == 0 => {
+                *b |= mask;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove(&mut self, idx: u8) {
+        if let Some(b) = self.0.get_mut(usize::from(idx / 8)) {
+            *b &= !(1 << (idx % 8));
+        }
+    }
+}
+
+// Right Ctrl and Alt (XT E0 1D, E0 38) are held apart from left Ctrl and Alt
+// (1D, 38), so releasing one doesn't let the other's repeats through.
+#[cfg(feature = "collapse-repeats")]
+const _: () = {
+    assert!(KeySet::index(0x1d, false) != KeySet::index(0x1d, true));
+    assert!(KeySet::index(0x38, false) != KeySet::index(0x38, true));
+    // Breaks index the same slot as their makes.
+    assert!(KeySet::index(0x1d | 0x80, true) == KeySet::index(0x1d, true));
+};
+
 enum State {
     NotInKey,
     SimpleKey(u8),
     PossibleBreakCode,
     KnownBreakCode(u8),
     UnmodifiedKey(u8),
+    // XT code; the E0 prefix is sent first. An XT host can't ask for a
+    // resend, so the only thing that can come between the two bytes is a
+    // host reset, and KeyboardReset abandons the sequence.
+    ExtendedKey(u8),
+    #[cfg(feature = "leds")]
     ToggleLedFirst(u8),
     // InPause(u8), // Number of keycodes in pause left to handle- alternate impl.
     Inconsistent,
     ExpectingBufferClear,
+    ExpectingReinit,
+    Idle,
+    Magic(MagicAction),
+    #[cfg(feature = "prefer-keyboard-set1")]
+    SelectingSet1,
+    // Idle ticks since the keyboard went missing, or since the last attempt
+    // to bring it back.
+    NoKeyboard(u8),
 }
 
 pub struct Fsm {
     curr_state: State,
     expecting_pause: bool,
+    #[cfg(feature = "leds")]
     led_mask: LedMask,
+    ticks_since_reset: u8,
+    // An E0 prefix was received. It isn't forwarded until the rest of the key
+    // arrives, so the whole key can be dealt with at once.
+    extended: bool,
+    // We reset the keyboard and its self-test result (0xaa) hasn't come back
+    // yet. Any other 0xaa means the keyboard reset itself.
+    expecting_bat: bool,
+    chord: Chord,
+    // Run once the key release that completed the chord has been sent.
+    magic_pending: Option<MagicAction>,
+    synthetic: XtQueue,
+    #[cfg(feature = "collapse-repeats")]
+    held: KeySet,
+    // The keyboard confirmed it's sending scancode set 1, which is already
+    // XT and needs no translation.
+    #[cfg(feature = "prefer-keyboard-set1")]
+    set1: bool,
+    // Scroll Lock is down, so its typematic repeats don't switch modes.
+    #[cfg(feature = "scroll-lock-as-mode")]
+    mode_key_held: bool,
 }
 
 impl Fsm {
-    #[allow(dead_code)]
     const ERROR1: u8 = 0x00;
+    #[cfg_attr(not(feature = "leds"), allow(dead_code))]
     const CAPS: u8 = 0x58;
     const NUM: u8 = 0x77;
     const SCROLL: u8 = 0x7e;
+    const LSHIFT: u8 = 0x12;
+    const RSHIFT: u8 = 0x59;
     const SELF_TEST_PASSED: u8 = 0xaa;
     const PREFIX: u8 = 0xe0;
     const PREFIX_PAUSE: u8 = 0xe1;
@@ -100,83 +493,483 @@ impl Fsm {
     #[allow(dead_code)]
     const SELF_TEST_FAILED2: u8 = 0xfd;
     const NAK: u8 = 0xfe;
-    #[allow(dead_code)]
     const ERROR2: u8 = 0xff;
+    // What an XT keyboard sends when its buffer overruns.
+    const XT_OVERRUN: u8 = 0xff;
+
+    // A second reset within this many idle ticks of the first is treated as
+    // a request for a full reinit.
+    const DOUBLE_RESET_TICKS: u8 = 5;
+
+    // Magic chords must be tapped out within about a second.
+    const MAGIC_WINDOW_TICKS: u8 = 10;
+
+    // How often to try reinitializing a missing keyboard: every 2 seconds.
+    const NO_KEYBOARD_RETRY_TICKS: u8 = 20;
 
     pub fn start() -> Fsm {
         Fsm {
             curr_state: State::NotInKey,
             expecting_pause: false,
+            #[cfg(feature = "leds")]
             led_mask: Default::default(),
+            ticks_since_reset: u8::MAX,
+            extended: false,
+            // main() resets the keyboard before starting us.
+            expecting_bat: true,
+            chord: Chord::new(),
+            magic_pending: None,
+            synthetic: XtQueue::new(),
+            #[cfg(feature = "collapse-repeats")]
+            held: Default::default(),
+            #[cfg(feature = "prefer-keyboard-set1")]
+            set1: false,
+            #[cfg(feature = "scroll-lock-as-mode")]
+            mode_key_held: false,
         }
     }
 
-    pub fn run(&mut self, curr_reply: &ProcReply) -> Result<Cmd, ()> {
-        let next_state = self.next_state(curr_reply);
+    // Queue an XT key that didn't come from the keyboard. It goes out through
+    // SendXtKey like any other, once no key from the keyboard is half-sent
+    // (e.g. after an E0 in set 1, or mid-Pause). Fails if the queue is full.
+    #[cfg_attr(not(feature = "macros"), allow(dead_code))]
+    pub fn emit_xt_key(&mut self, code: u8) -> Result<(), ()> {
+        self.synthetic.push(code)
+    }
+
+    pub fn run(&mut self, curr_reply: &ProcReply) -> Result<Cmd, FsmError> {
+        let next_state = match self.next_state(curr_reply) {
+            State::NotInKey if !self.extended && !self.expecting_pause => {
+                match self.synthetic.pop() {
+                    Some(u) => Self::synthetic_key(u),
+                    None => State::NotInKey,
+                }
+            }
+            s => s,
+        };
 
         let next_cmd = match next_state {
-            State::NotInKey | State::PossibleBreakCode => Ok(Cmd::WaitForKey),
-            State::SimpleKey(k) => keymap::to_xt(k).ok_or(()).map(Cmd::SendXtKey),
-            State::KnownBreakCode(b) => {
-                keymap::to_xt(b).ok_or(()).map(|b| Cmd::SendXtKey(b | 0x80))
+            // Keep waiting without a keyboard, so host resets still get
+            // answered and a newly plugged-in keyboard is noticed.
+            State::NotInKey | State::PossibleBreakCode | State::NoKeyboard(_) => {
+                Ok(Cmd::WaitForKey)
             }
+            State::SimpleKey(k) => keymap::to_xt(k)
+                .ok_or(FsmError::UnknownScancode)
+                .map(Cmd::SendXtKey),
+            State::KnownBreakCode(b) => keymap::to_xt(b)
+                .ok_or(FsmError::UnknownScancode)
+                .map(|b| Cmd::SendXtKey(b | 0x80)),
             State::UnmodifiedKey(u) => Ok(Cmd::SendXtKey(u)),
+            State::ExtendedKey(_) => Ok(Cmd::SendXtKey(Self::PREFIX)),
+            #[cfg(feature = "leds")]
             State::ToggleLedFirst(l) => match l {
                 Self::SCROLL => Ok(Cmd::ToggleLed(self.led_mask ^ LedMask::SCROLL)),
                 Self::NUM => Ok(Cmd::ToggleLed(self.led_mask ^ LedMask::NUM)),
                 Self::CAPS => Ok(Cmd::ToggleLed(self.led_mask ^ LedMask::CAPS)),
-                _ => Err(()),
+                _ => Err(FsmError::UnexpectedReply),
             },
             State::ExpectingBufferClear => Ok(Cmd::ClearBuffer),
-            State::Inconsistent => Err(()),
+            State::ExpectingReinit => Ok(Cmd::ReinitKeyboard),
+            State::Idle => Ok(Cmd::Idle),
+            State::Magic(a) => Ok(Cmd::Magic(a)),
+            #[cfg(feature = "prefer-keyboard-set1")]
+            State::SelectingSet1 => Ok(Cmd::SelectSet1),
+            State::Inconsistent => Err(FsmError::UnexpectedReply),
         };
 
-        self.curr_state = next_state;
+        // Don't get stuck after an error; start over as if waiting for a key,
+        // which is what the caller should do next.
+        if next_cmd.is_ok() {
+            self.curr_state = next_state;
+        } else {
+            self.curr_state = State::NotInKey;
+            self.extended = false;
+        }
+
         next_cmd
     }
 
+    // Each state's command (see run) is answered by the main loop with one
+    // reply, and any state can see KeyboardReset:
+    //
+    // NotInKey, PossibleBreakCode, NoKeyboard: WaitForKey -> GrabbedKey,
+    //     IdleTick or KeyboardAbsent
+    // SimpleKey, KnownBreakCode, UnmodifiedKey, ExtendedKey: SendXtKey ->
+    //     SentKey
+    // ToggleLedFirst: ToggleLed -> LedToggled
+    // ExpectingBufferClear: ClearBuffer -> ClearedBuffer
+    // ExpectingReinit: ReinitKeyboard -> KeyboardReinitialized
+    // SelectingSet1: SelectSet1 -> Set1Selected
+    // Idle: Idle -> NothingToDo
+    // Magic: Magic -> MagicDone
+    // Inconsistent: none; run starts over from NotInKey.
+    //
+    // NotInKey also sees NothingToDo, the first reply after start().
     fn next_state(&mut self, curr_reply: &ProcReply) -> State {
+        if let ProcReply::IdleTick | ProcReply::KeyboardAbsent = curr_reply {
+            self.ticks_since_reset = self.ticks_since_reset.saturating_add(1);
+            self.chord.tick();
+        }
+
         match (&self.curr_state, curr_reply) {
-            (_, &ProcReply::KeyboardReset) => State::ExpectingBufferClear,
+            (_, &ProcReply::KeyboardReset) => {
+                self.extended = false;
+                // Keys queued before the reset would reach the host after
+                // its 0xaa.
+                self.synthetic = XtQueue::new();
+                self.expecting_bat = true;
+                #[cfg(feature = "collapse-repeats")]
+                {
+                    self.held = Default::default();
+                }
+                #[cfg(feature = "scroll-lock-as-mode")]
+                {
+                    self.mode_key_held = false;
+                }
+                // The host comes back up with every lock off. The keyboard's
+                // LEDs catch up with the next lock key (e.g. boot-numlock-on's).
+                #[cfg(feature = "leds")]
+                {
+                    self.led_mask = Default::default();
+                }
+                // A reset puts the keyboard back on set 2.
+                #[cfg(feature = "prefer-keyboard-set1")]
+                {
+                    self.set1 = false;
+                }
+
+                if self.ticks_since_reset < Self::DOUBLE_RESET_TICKS {
+                    // Don't let a third reset count as another double.
+                    self.ticks_since_reset = u8::MAX;
+                    State::ExpectingReinit
+                } else {
+                    self.ticks_since_reset = 0;
+                    State::ExpectingBufferClear
+                }
+            }
+            (&State::ExpectingReinit, &ProcReply::KeyboardReinitialized) => {
+                // The main loop took the self-test result, if one was due,
+                // before reinitializing.
+                self.expecting_bat = false;
+                self.on_defaults(State::ExpectingBufferClear)
+            }
+            // Whatever the answer, drop the stray ACKs it may have left behind.
+            #[cfg(feature = "prefer-keyboard-set1")]
+            (&State::SelectingSet1, &ProcReply::Set1Selected(ok)) => {
+                self.set1 = ok;
+                State::ExpectingBufferClear
+            }
             (&State::NotInKey, &ProcReply::NothingToDo)
             | (&State::SimpleKey(_), &ProcReply::SentKey(_))
-            | (&State::KnownBreakCode(_), &ProcReply::SentKey(_))
             | (&State::UnmodifiedKey(_), &ProcReply::SentKey(_))
-            | (&State::ExpectingBufferClear, &ProcReply::ClearedBuffer) => State::NotInKey,
-            (&State::NotInKey, &ProcReply::GrabbedKey(k)) => {
+            | (&State::ExpectingBufferClear, &ProcReply::ClearedBuffer)
+            | (&State::Idle, &ProcReply::NothingToDo)
+            | (&State::Magic(_), &ProcReply::MagicDone) => State::NotInKey,
+            (&State::KnownBreakCode(_), &ProcReply::SentKey(_)) => {
+                // The host has the release; now act on any chord it finished.
+                self.magic_pending
+                    .take()
+                    .map_or(State::NotInKey, State::Magic)
+            }
+            (&State::ExtendedKey(k), &ProcReply::SentKey(_)) => State::UnmodifiedKey(k),
+            (&State::NotInKey, &ProcReply::IdleTick) => State::Idle,
+            (&State::NotInKey, &ProcReply::KeyboardAbsent) => State::NoKeyboard(0),
+            (&State::NoKeyboard(t), &ProcReply::KeyboardAbsent) => {
+                if t.saturating_add(1) >= Self::NO_KEYBOARD_RETRY_TICKS {
+                    // If it answers, it's back.
+                    State::ExpectingReinit
+                } else {
+                    State::NoKeyboard(t.saturating_add(1))
+                }
+            }
+            // Don't lose our place in the middle of a break code.
+            (&State::PossibleBreakCode, &ProcReply::IdleTick) => State::PossibleBreakCode,
+            // In set 1, 0xaa is also the release of left Shift, so only the
+            // expected self-test result is recognized.
+            #[cfg(feature = "prefer-keyboard-set1")]
+            (&State::NotInKey, &ProcReply::GrabbedKey(k))
+                if self.set1 && !(k == Self::SELF_TEST_PASSED && self.expecting_bat) =>
+            {
+                self.set1_code(k)
+            }
+            // A valid frame from a missing keyboard means it's back; the main
+            // loop stops counting it absent. One just plugged in sends an
+            // unexpected 0xaa, and gets set up again below.
+            (&State::NotInKey, &ProcReply::GrabbedKey(k))
+            | (&State::NoKeyboard(_), &ProcReply::GrabbedKey(k)) => {
                 match k {
                     // TODO: 0xfa, 0xfe, and 0xee should never be sent unprompted.
-                    Self::SELF_TEST_PASSED | Self::ACK | Self::NAK | Self::ECHO => State::NotInKey,
+                    Self::ACK | Self::NAK | Self::ECHO => State::NotInKey,
+                    // Overrun (0x00 in set 2, 0xff otherwise). Not a key, so
+                    // don't translate it.
+                    Self::ERROR1 | Self::ERROR2 => {
+                        self.extended = false;
+                        State::UnmodifiedKey(Self::XT_OVERRUN)
+                    }
+                    Self::SELF_TEST_PASSED => {
+                        if self.expecting_bat {
+                            self.expecting_bat = false;
+                            self.on_defaults(State::NotInKey)
+                        } else {
+                            // E.g. a laptop-style keyboard waking from sleep.
+                            // It's back to defaults; set it up again.
+                            self.extended = false;
+                            State::ExpectingReinit
+                        }
+                    }
                     Self::BREAK => State::PossibleBreakCode,
-                    Self::PREFIX => State::UnmodifiedKey(k),
+                    Self::PREFIX => {
+                        self.extended = true;
+                        State::NotInKey
+                    }
                     Self::PREFIX_PAUSE => {
                         self.expecting_pause = true;
                         State::UnmodifiedKey(k)
                     }
+                    #[cfg(feature = "scroll-lock-as-mode")]
+                    Self::SCROLL if !self.extended => self.mode_key(),
 
-                    _ => State::SimpleKey(k),
+                    _ => self.make_code(k),
                 }
             }
             (&State::PossibleBreakCode, &ProcReply::GrabbedKey(k)) => {
                 match k {
+                    // Extended keys never toggle LEDs (e.g. E0 7E is Ctrl+Break).
+                    _ if self.extended => self.break_code(k),
+                    #[cfg(feature = "scroll-lock-as-mode")]
+                    Self::SCROLL => self.mode_key_released(),
                     // LEDs => State::ToggleLed()
-                    Self::SCROLL | Self::CAPS => State::ToggleLedFirst(k),
-                    Self::NUM => {
-                        if self.expecting_pause {
-                            self.expecting_pause = false;
-                            State::KnownBreakCode(k)
-                        } else {
-                            State::ToggleLedFirst(k)
-                        }
+                    #[cfg(all(feature = "leds", not(feature = "scroll-lock-as-mode")))]
+                    Self::SCROLL => State::ToggleLedFirst(k),
+                    #[cfg(feature = "leds")]
+                    Self::CAPS => State::ToggleLedFirst(k),
+                    // Part of Pause, not Num Lock.
+                    Self::NUM if self.expecting_pause => {
+                        self.expecting_pause = false;
+                        self.untapped_break_code(k)
                     }
-                    _ => State::KnownBreakCode(k),
+                    #[cfg(feature = "leds")]
+                    Self::NUM => State::ToggleLedFirst(k),
+                    _ => self.break_code(k),
                 }
             }
+            #[cfg(feature = "leds")]
             (&State::ToggleLedFirst(l), &ProcReply::LedToggled(m)) => {
                 self.led_mask = m;
-                State::KnownBreakCode(l)
+                self.break_code(l)
             }
-            (_, _) => State::Inconsistent,
+            // Any other reply is one the state's command can't lead to.
+            // Listing the states, rather than matching (_, _), makes adding
+            // a state a compile error here until its transitions are written.
+            (&State::NotInKey, _)
+            | (&State::SimpleKey(_), _)
+            | (&State::PossibleBreakCode, _)
+            | (&State::KnownBreakCode(_), _)
+            | (&State::UnmodifiedKey(_), _)
+            | (&State::ExtendedKey(_), _)
+            | (&State::Inconsistent, _)
+            | (&State::ExpectingBufferClear, _)
+            | (&State::ExpectingReinit, _)
+            | (&State::Idle, _)
+            | (&State::Magic(_), _)
+            | (&State::NoKeyboard(_), _) => State::Inconsistent,
+            #[cfg(feature = "leds")]
+            (&State::ToggleLedFirst(_), _) => State::Inconsistent,
+            #[cfg(feature = "prefer-keyboard-set1")]
+            (&State::SelectingSet1, _) => State::Inconsistent,
+        }
+    }
+
+    // The keyboard is on its power-on defaults (scancode set 2). Normally we
+    // carry on to next; with prefer-keyboard-set1, try for set 1 first.
+    #[cfg(feature = "prefer-keyboard-set1")]
+    fn on_defaults(&mut self, _next: State) -> State {
+        self.set1 = false;
+        State::SelectingSet1
+    }
+
+    #[cfg(not(feature = "prefer-keyboard-set1"))]
+    fn on_defaults(&mut self, next: State) -> State {
+        next
+    }
+
+    // In set 1 the keyboard already sends XT codes, so they are passed
+    // through untouched. Prefixes are only tracked so that lock key releases
+    // still drive the LEDs. collapse-repeats doesn't apply here, and fake
+    // shifts get through, as their E0 has gone out before they're seen.
+    #[cfg(feature = "prefer-keyboard-set1")]
+    fn set1_code(&mut self, k: u8) -> State {
+        match k {
+            Self::ACK | Self::NAK | Self::ECHO => State::NotInKey,
+            Self::ERROR1 | Self::ERROR2 => {
+                self.extended = false;
+                State::UnmodifiedKey(Self::XT_OVERRUN)
+            }
+            Self::PREFIX => {
+                self.extended = true;
+                State::UnmodifiedKey(k)
+            }
+            Self::PREFIX_PAUSE => {
+                self.expecting_pause = true;
+                State::UnmodifiedKey(k)
+            }
+            _ => {
+                #[cfg_attr(
+                    not(any(feature = "leds", feature = "scroll-lock-as-mode")),
+                    allow(unused_variables)
+                )]
+                let extended = core::mem::replace(&mut self.extended, false);
+                // Pause ends in C5, which isn't a Num Lock release.
+                #[cfg_attr(not(feature = "leds"), allow(unused_variables))]
+                let pause = k == 0xc5 && core::mem::replace(&mut self.expecting_pause, false);
+
+                #[cfg(feature = "scroll-lock-as-mode")]
+                match k {
+                    0x46 if !extended => return self.mode_key(),
+                    0xc6 if !extended => return self.mode_key_released(),
+                    _ => {}
+                }
+
+                #[cfg(feature = "leds")]
+                if !extended && !pause {
+                    if let Some(l) = Self::lock_release(k) {
+                        return State::ToggleLedFirst(l);
+                    }
+                }
+
+                State::UnmodifiedKey(k)
+            }
+        }
+    }
+
+    // We reset the keyboard and its self-test result hasn't come back yet.
+    pub fn expecting_bat(&self) -> bool {
+        self.expecting_bat
+    }
+
+    // With scroll-lock-as-mode, Scroll Lock never reaches the host. Pressing
+    // it switches to the next XT timing profile instead, like the Num Lock
+    // chord, and its release is swallowed. Its own tap chord can't complete.
+    #[cfg(feature = "scroll-lock-as-mode")]
+    fn mode_key(&mut self) -> State {
+        if core::mem::replace(&mut self.mode_key_held, true) {
+            State::NotInKey
+        } else {
+            State::Magic(MagicAction::NextXtProfile)
+        }
+    }
+
+    #[cfg(feature = "scroll-lock-as-mode")]
+    fn mode_key_released(&mut self) -> State {
+        self.mode_key_held = false;
+        State::NotInKey
+    }
+
+    // What the keyboard's LEDs show.
+    #[cfg(feature = "leds")]
+    #[cfg_attr(not(feature = "scroll-lock-as-mode"), allow(dead_code))]
+    pub fn led_mask(&self) -> LedMask {
+        self.led_mask
+    }
+
+    // Set 2 make code of the lock key an XT release code belongs to.
+    // ToggleLedFirst wants the set 2 code, and sends its release (the same
+    // XT byte) once the LEDs are done.
+    #[cfg(feature = "leds")]
+    fn lock_release(xt_code: u8) -> Option<u8> {
+        match xt_code {
+            0xba => Some(Self::CAPS),
+            0xc5 => Some(Self::NUM),
+            0xc6 => Some(Self::SCROLL),
+            _ => None,
+        }
+    }
+
+    // A code from emit_xt_key goes out as is, except that lock key releases
+    // (e.g. boot-numlock-on's) drive the LEDs as if typed.
+    #[cfg(feature = "leds")]
+    fn synthetic_key(u: u8) -> State {
+        Self::lock_release(u).map_or(State::UnmodifiedKey(u), State::ToggleLedFirst)
+    }
+
+    #[cfg(not(feature = "leds"))]
+    fn synthetic_key(u: u8) -> State {
+        State::UnmodifiedKey(u)
+    }
+
+    // The keyboard wraps the gray navigation keys (Insert, Delete, Home, End,
+    // Page Up/Down, the arrows), keypad / and Print Screen in E0 12 and E0 59
+    // makes and breaks: "fake" left and right shifts that undo Shift, or
+    // cancel Num Lock, for the benefit of software expecting an 83-key
+    // keyboard. There are no such keys. Forwarded, they'd be E0 2A/E0 36, and
+    // an XT BIOS that ignores E0 sees a real Shift, so they're dropped.
+    const fn is_fake_shift(k: u8, extended: bool) -> bool {
+        extended && (k == Self::LSHIFT || k == Self::RSHIFT)
+    }
+
+    fn make_code(&mut self, k: u8) -> State {
+        let extended = core::mem::replace(&mut self.extended, false);
+
+        if Self::is_fake_shift(k, extended) {
+            return State::NotInKey;
+        }
+
+        // By default every typematic repeat is forwarded as another make,
+        // just as an XT keyboard does its own auto-repeat. With
+        // collapse-repeats, forward the first make of a held key, but swallow
+        // repeats until it's released.
+        #[cfg(feature = "collapse-repeats")]
+        if let Some(xt) = keymap::to_xt(k) {
+            if !self.held.insert(KeySet::index(xt, extended)) {
+                return State::NotInKey;
+            }
+        }
+
+        // Extended keys share the XT code of their non-extended twin and are
+        // told apart by the E0 prefix alone, e.g. AltGr (AT E0 11) is sent as
+        // XT E0 38 while left Alt (AT 11) is a bare 38.
+        if extended {
+            keymap::to_xt(k).map_or(State::Inconsistent, State::ExtendedKey)
+        } else {
+            State::SimpleKey(k)
+        }
+    }
+
+    // A release, which also counts towards MAGIC_CHORDS.
+    fn break_code(&mut self, k: u8) -> State {
+        if !self.extended {
+            self.magic_pending = self.chord.release(k);
+        }
+
+        self.untapped_break_code(k)
+    }
+
+    // A release that isn't a tap of its key, e.g. the F0 77 ending Pause.
+    fn untapped_break_code(&mut self, k: u8) -> State {
+        let extended = core::mem::replace(&mut self.extended, false);
+
+        if Self::is_fake_shift(k, extended) {
+            return State::NotInKey;
+        }
+
+        #[cfg(feature = "collapse-repeats")]
+        if let Some(xt) = keymap::to_xt(k) {
+            self.held.remove(KeySet::index(xt, extended));
+        }
+
+        if extended {
+            keymap::to_xt(k).map_or(State::Inconsistent, |b| State::ExtendedKey(b | 0x80))
+        } else {
+            State::KnownBreakCode(k)
         }
     }
 }
+
+// Only E0 12 and E0 59 are fake. Plain 12 and 59 are the real shifts, and
+// other E0 keys (here right Ctrl) are real too.
+const _: () = {
+    assert!(Fsm::is_fake_shift(Fsm::LSHIFT, true) && Fsm::is_fake_shift(Fsm::RSHIFT, true));
+    assert!(!Fsm::is_fake_shift(Fsm::LSHIFT, false) && !Fsm::is_fake_shift(Fsm::RSHIFT, false));
+    assert!(!Fsm::is_fake_shift(0x14, true));
+};
@@ -0,0 +1,32 @@
+// A fixed sequence of XT codes, typed to the host when Caps Lock is tapped
+// four times (MagicAction::PlayMacro). Edit MACRO to taste; it lives in
+// flash, so keep it short.
+use crate::keyfsm::Fsm;
+
+// "DIR", Enter: each key's make, then its release.
+const MACRO: [u8; 8] = [0x20, 0xa0, 0x17, 0x97, 0x13, 0x93, 0x1c, 0x9c];
+
+pub struct Player {
+    pos: u8, // Next code in MACRO to send, or past the end when idle.
+}
+
+impl Player {
+    pub const fn new() -> Player {
+        Player { pos: u8::MAX }
+    }
+
+    pub fn start(&mut self) {
+        self.pos = 0;
+    }
+
+    // Queue the next code with the FSM, if there is one. Called once per
+    // idle tick, so codes go out at most every IDLE_TICK, and only while the
+    // keyboard is quiet.
+    pub fn feed(&mut self, fsm: &mut Fsm) {
+        if let Some(&code) = MACRO.get(usize::from(self.pos)) {
+            if fsm.emit_xt_key(code).is_ok() {
+                self.pos = self.pos.saturating_add(1);
+            }
+        }
+    }
+}
@@ -3,20 +3,25 @@
 #![feature(abi_msp430_interrupt)]
 #![deny(unsafe_code)]
 
-extern crate panic_msp430;
-
 use bit_reverse::BitwiseReverse;
 use core::cell::{Cell, RefCell};
+use core::convert::TryFrom;
 use msp430::{critical_section as mspcs, interrupt::CriticalSection, interrupt::Mutex};
 use msp430_rt::entry;
-use msp430g2211::{interrupt, Peripherals};
 use portable_atomic::{AtomicBool, Ordering};
 
+mod mcu;
+use mcu::pac::{self, interrupt, Peripherals};
+
 mod keyfsm;
-use keyfsm::{Cmd, Fsm, LedMask, ProcReply};
+#[cfg(feature = "leds")]
+use keyfsm::LedMask;
+use keyfsm::{Cmd, Fsm, MagicAction, ProcReply};
 
 mod keybuffer;
-use keybuffer::{KeyIn, KeyOut, KeycodeBuffer};
+use keybuffer::{KeyIn, KeycodeBuffer};
+
+mod host_mode;
 
 mod driver;
 use driver::Pins;
@@ -24,20 +29,313 @@ use driver::Pins;
 mod peripheral;
 use peripheral::At2XtPeripherals;
 
+mod util;
+
+mod diagnostics;
+use diagnostics::ResetReason;
+
+mod eventlog;
+use eventlog::Event;
+
+mod panic;
+
+#[cfg(feature = "macros")]
+mod macros;
+
+// Clock tree set up by init(): the DCO is boosted from its 1MHz calibration
+// by RSEL_BOOST RSEL steps (and one DCO step down) to at least
+// CLOCK_TARGET_HZ. SMCLK is DCO / SMCLK_DIV, and the timer is SMCLK /
+// TIMER_DIV, each 1, 2, 4 or 8; init() sets DIVS and ID from them, and
+// everything derived from TIMER_TARGET_HZ (delay_us!, IDLE_TICK) follows
+// automatically.
+const CLOCK_TARGET_HZ: u32 = 1_600_000;
+const RSEL_BOOST: u8 = 2;
+const SMCLK_DIV: u32 = 4;
+const TIMER_DIV: u32 = 4;
+const TIMER_TARGET_HZ: u32 = 100_000;
+#[allow(clippy::as_conversions)]
+const TIMER_TICK_US: u16 = (1_000_000 / TIMER_TARGET_HZ) as u16;
+
+// Used when the 1MHz calibration constants have been erased: XT2 off,
+// RSEL 9, DCO 3, MOD 0. Per the datasheet's DCO table this is roughly 2MHz;
+// delays will run short, but the converter will at least come up.
+const FALLBACK_BCSCTL1: u8 = 0x89;
+const FALLBACK_DCOCTL: u8 = 0x60;
+
+// Each RSEL step multiplies the DCO by 1.35, each DCO step by 1.08.
+const fn boosted_dco_hz(rsel_steps: u8) -> u32 {
+    let mut hz = 1_000_000 * 100 / 108;
+    let mut i = 0;
+
+    while i < rsel_steps {
+        hz = hz * 135 / 100;
+        i += 1;
+    }
+
+    hz
+}
+
+// The 1MHz calibration can be off by up to 3%.
+const _: () = assert!(boosted_dco_hz(RSEL_BOOST) * 97 / 100 >= CLOCK_TARGET_HZ);
+const _: () = assert!(CLOCK_TARGET_HZ / SMCLK_DIV / TIMER_DIV == TIMER_TARGET_HZ);
+const _: () = assert!(1_000_000 % TIMER_TARGET_HZ == 0);
+
+// The DIVS or ID field value for a divider.
+const fn div_field(div: u32) -> u8 {
+    match div {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        _ => panic!("clock dividers are 1, 2, 4 or 8"),
+    }
+}
+
+const SMCLK_DIVS: u8 = div_field(SMCLK_DIV);
+const TIMER_ID: u8 = div_field(TIMER_DIV);
+
 macro_rules! delay_us {
     ($u:expr) => {
-        // Timer is 100000 Hz, thus granularity of 10us.
-        delay(($u / 10) + 1)
+        delay(($u / TIMER_TICK_US) + 1)
     };
 }
 
+// The XT_CLK edge on which the host latches XT_DATA. For Rising (IBM and
+// most clones), DATA is driven right before CLK falls and is stable for the
+// whole low half of the clock. Hosts that latch on the falling edge need DATA
+// to settle while CLK is still high, so Falling holds DATA for a half-bit
+// before pulling CLK low.
+#[derive(Clone, Copy)]
+enum XtClockEdge {
+    Rising,
+    Falling,
+}
+
+// Everything about sending to the XT host that differs between hosts.
+#[derive(Clone, Copy)]
+struct XtTimingProfile {
+    clock_edge: XtClockEdge,
+    // Length of each half of an XT_CLK cycle.
+    half_bit_us: u16,
+    // How long to keep driving XT_DATA at the last bit's level after the
+    // final clock, before releasing the lines. IBM hosts latch on the clock
+    // edge and need no hold. A host that samples DATA late sees it released
+    // (high), so every key arrives with bit 7 set and looks like a release.
+    // Trailing clocks would leave DATA high before the hold, so a profile
+    // can't have both.
+    data_hold_us: u16,
+    // Extra clock pulses, with DATA high, after each byte, for the odd clone
+    // that wants idle clocks between bytes. Standard hosts want none.
+    trailing_clocks: u8,
+}
+
+// IBM 5150/5160 and most clones.
+const XT_PROFILE_IBM: XtTimingProfile = XtTimingProfile {
+    clock_edge: XtClockEdge::Rising,
+    half_bit_us: 55,
+    data_hold_us: 0,
+    trailing_clocks: 0,
+};
+
+// Clones that latch late: on the falling edge, and with DATA still held.
+// Try this if keys seem to do nothing.
+const XT_PROFILE_LATE_LATCH: XtTimingProfile = XtTimingProfile {
+    clock_edge: XtClockEdge::Falling,
+    half_bit_us: 55,
+    data_hold_us: 50,
+    trailing_clocks: 0,
+};
+
+// Hosts whose shift register keeps up with a quicker clock; about a quarter
+// less time per key.
+const XT_PROFILE_FAST: XtTimingProfile = XtTimingProfile {
+    clock_edge: XtClockEdge::Rising,
+    half_bit_us: 40,
+    data_hold_us: 0,
+    trailing_clocks: 0,
+};
+
+// The first is used at power on; the NextXtProfile magic chord steps through
+// the rest. The choice isn't saved across power cycles.
+const XT_PROFILES: [XtTimingProfile; 3] = [XT_PROFILE_IBM, XT_PROFILE_LATE_LATCH, XT_PROFILE_FAST];
+const _: () = {
+    let mut rest: &[XtTimingProfile] = &XT_PROFILES;
+
+    while let [p, tail @ ..] = rest {
+        assert!(p.data_hold_us == 0 || p.trailing_clocks == 0);
+        rest = tail;
+    }
+};
+
+// How many more times to try a key the host won't take (it holds XT_DATA low
+// past HOST_BUSY_TIMEOUT) before dropping it, and the wait before the first
+// retry, in timer ticks (10us). The wait doubles after each retry, up to the
+// most delay() takes, so by default a key is given up on after about 2s.
+// With boot-replay, such keys are queued instead.
+#[cfg_attr(feature = "boot-replay", allow(dead_code))]
+const XT_SEND_RETRIES: u8 = 8;
+#[cfg_attr(feature = "boot-replay", allow(dead_code))]
+const XT_SEND_BACKOFF: u16 = 1000;
+
+// How many HOST_BUSY_TIMEOUTs send_byte_to_pc_blocking waits out for a host
+// that won't take its byte before giving up: about a second.
+const HOST_REPLY_TRIES: u8 = 100;
+
+// The XT protocol sends data bits LSB first. Set this for a nonstandard host
+// that shifts them in MSB first. Only the data bits are affected; the start
+// bits and xt-parity's parity bit go out as usual.
+const XT_MSB_FIRST: bool = false;
+
+// Cap on keys sent to the host per second, for hosts whose keyboard ISR
+// can't keep up with bursts even though each byte is handshaken. 0 for no
+// cap. The interval between keys must be at least a tick, and fit in a
+// delay(): at least 2 at 100kHz.
+const XT_MAX_KPS: u16 = 0;
+#[allow(clippy::as_conversions)]
+const XT_KEY_INTERVAL: u16 = match TIMER_TARGET_HZ.checked_div(XT_MAX_KPS as u32) {
+    Some(i) => {
+        assert!(i != 0 && i <= u16::MAX as u32);
+        i as u16
+    }
+    None => 0,
+};
+
+// What to do when a frame from the keyboard has a bad start, parity, or stop
+// bit. Either way, MAX_BAD_FRAMES in a row resets the keyboard.
+#[allow(dead_code)]
+enum FramingPolicy {
+    Drop,
+    Resend, // Ask the keyboard to send the byte again.
+}
+
+const FRAMING_POLICY: FramingPolicy = FramingPolicy::Resend;
+const MAX_BAD_FRAMES: u8 = 3;
+
+// How often WaitForKey hands control back to the FSM when no key is pending,
+// so periodic maintenance can run: 100ms.
+#[allow(clippy::as_conversions)]
+const IDLE_TICK: u16 = (TIMER_TARGET_HZ / 10) as u16;
+
+// Minimum time PORT1 keeps the keyboard inhibited after taking a frame,
+// spacing frames out under fast input. The keyboard holds keys while
+// inhibited, so any value is safe as far as it's concerned. But the ISR spins
+// with interrupts off for the whole hold, so keep it short.
+const AT_INHIBIT_HOLD_US: u16 = 0;
+
+// Whether to answer a host reset with SELF_TEST_PASSED, as a real XT keyboard
+// does, and how long to wait first, in timer ticks (10us). For the odd host
+// that chokes on the answer, or on getting it too soon after the reset.
+// With it off, self-test-status's extra byte isn't sent either.
+const SEND_SELF_TEST_ON_RESET: bool = true;
+const SELF_TEST_DELAY: u16 = 0;
+
+// Flow control for sustained fast input: once IN_BUFFER holds
+// AT_HOLD_OFF_HIGH frames, PORT1 leaves the keyboard inhibited after taking
+// one, so it holds keys itself rather than them being dropped. WaitForKey
+// lets it go once it has taken the buffer down to AT_HOLD_OFF_LOW. Releasing
+// as soon as there's a free slot would flip the lines on every frame.
+const AT_HOLD_OFF_HIGH: u8 = 12;
+const AT_HOLD_OFF_LOW: u8 = 4;
+const _: () =
+    assert!(AT_HOLD_OFF_LOW < AT_HOLD_OFF_HIGH && AT_HOLD_OFF_HIGH <= KeycodeBuffer::CAPACITY);
+
+// Number of consecutive low reads of AT_CLK required before a PORT1
+// interrupt is treated as a real clock edge. The keyboard holds AT_CLK low
+// for at least 30us per bit; a handful of reads takes a few us.
+#[cfg(feature = "at-debounce")]
+const AT_DEBOUNCE_SAMPLES: u8 = 4;
+
+// How long the host may hold XT_CLK/XT_DATA low (e.g. while it hasn't read
+// the last key yet) before send_byte_to_pc gives up on it: 10ms.
+#[allow(clippy::as_conversions)]
+const HOST_BUSY_TIMEOUT: u16 = (TIMER_TARGET_HZ / 100) as u16;
+
+// The keyboard must answer a command within 20ms; allow a bit more.
+#[allow(clippy::as_conversions)]
+const KBD_REPLY_TIMEOUT: u16 = (TIMER_TARGET_HZ / 1000 * 25) as u16;
+
+// How long after its last clock edge a partly received frame is given up
+// on, e.g. because the keyboard was unplugged mid-frame: 200ms, well before
+// a replugged keyboard finishes its self-test. It's checked every IDLE_TICK
+// against ticks(), which only moves when a wait ends, so a frame may be
+// dropped as much as one IDLE_TICK sooner. That must still leave longer than
+// the longest frame, 11 bits at 10kHz: 1.1ms.
+const KEY_IN_TIMEOUT: u32 = TIMER_TARGET_HZ / 1000 * 200;
+#[allow(clippy::as_conversions)]
+const _: () = assert!(KEY_IN_TIMEOUT > IDLE_TICK as u32 + TIMER_TARGET_HZ / 1000 * 2);
+
+// How many resets in a row the keyboard must pass at boot before it's
+// trusted. 1 trusts the first; raise it for marginal keyboards that only
+// sometimes come up right. A failed self-test starts the count over.
+const RESET_CONFIRMATIONS: u8 = 1;
+const _: () = assert!(RESET_CONFIRMATIONS >= 1);
+// Resets tried in all before giving up on confirming the keyboard, so that
+// one that always fails its self-test can't keep the host from being
+// answered.
+const RESET_ATTEMPTS: u8 = RESET_CONFIRMATIONS.saturating_mul(3);
+
+// How many times send_at_command sends a byte again when the keyboard asks
+// for a resend, before giving up on it.
+#[cfg_attr(not(feature = "prefer-keyboard-set1"), allow(dead_code))]
+const AT_COMMAND_RETRIES: u8 = 2;
+
+// Consecutive keyboard commands that go unanswered before the keyboard is
+// considered absent, until it next sends a valid frame. A keyboard that's
+// there answers everything except while it runs its self-test, so a single
+// command sent then isn't enough; raise this for flaky ones.
+const KBD_ABSENT_THRESHOLD: u8 = 2;
+
+// Grounded at boot to run loopback_test instead of the converter.
+#[cfg(feature = "loopback-test")]
+const LOOPBACK_JUMPER: Pins = Pins::UNUSED_6;
+
+// Idle ticks without a key before kbd-powersave stops the keyboard: 5 minutes.
+#[cfg(feature = "kbd-powersave")]
+const POWERSAVE_IDLE_TICKS: u16 = 5 * 60 * 10;
+
+// How long XT_CLK must stay low to count as a reset: a bit over half of the
+// 20ms the host holds it for, as we may not notice it go low right away.
+#[allow(clippy::as_conversions)]
+const HOST_RESET_HOLD: u16 = (TIMER_TARGET_HZ / 1000 * 12) as u16;
+
+// How long past a reset's hold XT_CLK may stay low before it's taken as held
+// (see xt_held_long) rather than a reset in progress: 50ms. Real resets end
+// at 20ms.
+#[allow(clippy::as_conversions)]
+const XT_STUCK_HOLD: u16 = (TIMER_TARGET_HZ / 1000 * 50) as u16;
+
 static TIMEOUT: AtomicBool = AtomicBool::new(false);
-static HOST_MODE: AtomicBool = AtomicBool::new(false);
-static DEVICE_ACK: AtomicBool = AtomicBool::new(false);
+// PORT1 left the keyboard inhibited because IN_BUFFER was filling up.
+static AT_HELD_OFF: AtomicBool = AtomicBool::new(false);
+// The host has held XT_CLK low for longer than any reset, and hasn't let go
+// yet; see xt_held_long.
+static XT_HELD_OFF: AtomicBool = AtomicBool::new(false);
+// Cleared by a keyboard reset, and set again once the FSM has its self-test
+// result. Keys are not forwarded to the host in between; they were typed
+// before the reset.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 static IN_BUFFER: Mutex<RefCell<KeycodeBuffer>> = Mutex::new(RefCell::new(KeycodeBuffer::new()));
 static KEY_IN: Mutex<Cell<KeyIn>> = Mutex::new(Cell::new(KeyIn::new()));
-static KEY_OUT: Mutex<Cell<KeyOut>> = Mutex::new(Cell::new(KeyOut::new()));
+// Keyboard commands in a row that the keyboard didn't acknowledge.
+static KBD_UNANSWERED: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+// now() when the last XT key started going out; see xt_rate_limit.
+static LAST_XT_KEY: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// Index into XT_PROFILES.
+static XT_PROFILE: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+// Timer ticks waited out so far; see ticks().
+static TICKS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// How many times start_timer has run, so that a Wait can tell another one
+// has taken the timer over.
+static TIMER_STARTS: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+// ticks() at the last clock edge PORT1 shifted into KEY_IN.
+static AT_LAST_EDGE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+// Timer count at the first clock edge of the frame PORT1 is shifting in.
+#[cfg(feature = "timing-diag")]
+static AT_FRAME_START: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+// XT keys waiting for the host to become ready, in the order they were sent.
+#[cfg(feature = "boot-replay")]
+static PRE_READY: Mutex<RefCell<KeycodeBuffer>> = Mutex::new(RefCell::new(KeycodeBuffer::new()));
 
 #[interrupt]
 fn TIMERA0(cs: CriticalSection) {
@@ -45,9 +343,16 @@ fn TIMERA0(cs: CriticalSection) {
 
     // Use unwrap b/c within interrupt handlers, if we can't get access to
     // peripherals right away, there's no point in continuing.
-    let timer: &msp430g2211::TIMER_A2 = At2XtPeripherals::periph_ref(cs).unwrap();
-    // Writing 0x0000 stops Timer in MC1.
+    let timer: &pac::TIMER_A2 = At2XtPeripherals::periph_ref(cs).unwrap();
+    // TAR just counted up to the compare value from 0 (start_timer clears
+    // it), so that's how long the wait took.
+    let waited = u32::from(timer.taccr0.read().taccr0().bits());
+    let ticks = TICKS.borrow(cs);
+    ticks.set(ticks.get().wrapping_add(waited));
+    // Writing 0x0000 stops Timer in MC1. Clear TAR too, so that the next
+    // start_timer doesn't count this wait again.
     timer.taccr0.write(|w| w.taccr0().bits(0x0000));
+    timer.tactl.modify(|_, w| w.taclr().set_bit());
     // CCIFG will be reset when entering interrupt; no need to clear it.
     // Nesting is disabled, and chances of receiving second CCIFG in the ISR
     // are nonexistant.
@@ -55,12 +360,19 @@ fn TIMERA0(cs: CriticalSection) {
 
 #[interrupt]
 fn PORT1(cs: CriticalSection) {
+    #[cfg(feature = "profiling")]
+    let entered = now(cs);
     let port = At2XtPeripherals::periph_ref(cs).unwrap();
 
-    if HOST_MODE.load(Ordering::SeqCst) {
-        let mut keyout = KEY_OUT.borrow(cs).get();
+    // Reject glitches on long cables: a spurious edge won't hold AT_CLK low.
+    #[cfg(feature = "at-debounce")]
+    if !driver::is_unset_stable(port, Pins::AT_CLK, AT_DEBOUNCE_SAMPLES) {
+        driver::clear_at_clk_int(port);
+        return;
+    }
 
-        if let Some(k) = keyout.shift_out() {
+    if host_mode::is_active() {
+        if let Some((k, last)) = host_mode::next_bit(cs) {
             if k {
                 driver::set(port, Pins::AT_DATA);
             } else {
@@ -68,29 +380,37 @@ fn PORT1(cs: CriticalSection) {
             }
 
             // Immediately after sending out the Stop Bit, we should release the lines.
-            if keyout.is_empty() {
+            if last {
                 driver::at_idle(port);
             }
         } else {
             // TODO: Is it possible to get a spurious clock interrupt and
-            // thus skip this logic?
+            // thus skip this logic? The at-debounce feature filters out
+            // short glitches before we get here.
             if driver::is_unset(port, Pins::AT_DATA) {
-                DEVICE_ACK.store(true, Ordering::SeqCst);
-                keyout.clear();
+                host_mode::ack(cs);
             }
         }
-
-        KEY_OUT.borrow(cs).set(keyout);
     } else {
         let mut keyin = KEY_IN.borrow(cs).get();
 
         // Are the buffer functions safe in nested interrupts? Is it possible to use tokens/manual
         // sync for nested interrupts while not giving up safety?
         // Example: Counter for nest level when updating buffers. If it's ever more than one, panic.
-        if keyin.shift_in(driver::is_set(port, Pins::AT_DATA)).is_err() {
+        #[cfg(feature = "timing-diag")]
+        let first_edge = keyin.is_empty();
+        let full = keyin.shift_in(driver::is_set(port, Pins::AT_DATA)).is_err();
+        AT_LAST_EDGE.borrow(cs).set(ticks(cs));
+
+        #[cfg(feature = "timing-diag")]
+        time_at_clock(cs, first_edge, full);
+
+        if full {
             driver::at_inhibit(port); // Ask keyboard to not send anything while processing keycode.
+            let mut hold_off = false;
 
             if let Some(k) = keyin.take() {
+                eventlog::log(Event::AtReceived);
                 if let Ok(mut b) = IN_BUFFER.borrow(cs).try_borrow_mut() {
                     // Dropping keys when the buffer is full is in line
                     // with what AT/XT hosts do. Saves 2 bytes on panic :)!
@@ -98,31 +418,90 @@ fn PORT1(cs: CriticalSection) {
                     {
                         let _ = b.put(k);
                     }
+                    hold_off = b.len() >= AT_HOLD_OFF_HIGH;
                 }
             }
 
-            keyin.clear();
+            // Can't wait on the timer in here; its interrupt won't nest.
+            if AT_INHIBIT_HOLD_US != 0 {
+                spin_delay(AT_INHIBIT_HOLD_US / TIMER_TICK_US);
+            }
 
-            driver::at_idle(port);
+            if hold_off {
+                AT_HELD_OFF.store(true, Ordering::SeqCst);
+            } else {
+                driver::at_idle(port);
+            }
         }
 
         KEY_IN.borrow(cs).set(keyin);
     }
 
     driver::clear_at_clk_int(port);
+
+    #[cfg(feature = "profiling")]
+    if let Some(t) = elapsed_since(cs, entered) {
+        diagnostics::record_isr_time(cs, t);
+    }
+}
+
+// Time a frame from the keyboard by the timer count at its first and last
+// clock edges. The timer only counts while something's waiting on it, e.g.
+// WaitForKey's idle tick, so frames it stopped or restarted during are
+// skipped. So are frames slower than 5ms, well past the 1.1ms spec limit.
+#[cfg(feature = "timing-diag")]
+fn time_at_clock(cs: CriticalSection, first_edge: bool, last_edge: bool) {
+    #[allow(clippy::as_conversions)]
+    const MAX_FRAME_TICKS: u16 = (TIMER_TARGET_HZ / 1000 * 5) as u16;
+
+    let timer: &pac::TIMER_A2 = match At2XtPeripherals::periph_ref(cs) {
+        Some(t) => t,
+        None => return,
+    };
+    let now = timer.tar.read().tar().bits();
+    let start = AT_FRAME_START.borrow(cs);
+
+    if first_edge {
+        start.set(now);
+    } else if last_edge {
+        if let Some(ticks @ 1..=MAX_FRAME_TICKS) = now.checked_sub(start.get()) {
+            let period_us = ticks * TIMER_TICK_US / u16::from(KeyIn::FRAME_BITS - 1);
+            diagnostics::record_at_clock_period(cs, period_us);
+        }
+    }
 }
 
 fn init(cs: CriticalSection) {
-    let p = Peripherals::take().unwrap();
+    // Only possible if something ran before us and took the peripherals.
+    // Without them we can't do anything useful, but the watchdog is still
+    // running from reset, so wait for it to give us a clean boot.
+    let p = match Peripherals::take() {
+        Some(p) => p,
+        None => loop {
+            msp430::asm::nop();
+        },
+    };
 
     p.WATCHDOG_TIMER
         .wdtctl
         .write(|w| w.wdtpw().password().wdthold().set_bit());
 
+    // The reset flags are sticky; clear them so the next reset reads fresh.
+    let reason = ResetReason::from_ifg1(&p.SPECIAL_FUNCTION.ifg1.read());
+    p.SPECIAL_FUNCTION.ifg1.modify(|_, w| {
+        w.wdtifg()
+            .clear_bit()
+            .porifg()
+            .clear_bit()
+            .rstifg()
+            .clear_bit()
+    });
+    diagnostics::set_reset_reason(cs, reason);
+
     driver::idle(&p.PORT_1_2);
 
     let calcb1 = p.CALIBRATION_DATA.calbc1_1mhz.read().calbc1_1mhz().bits();
-    let caldco = p.CALIBRATION_DATA.calbc1_1mhz.read().calbc1_1mhz().bits();
+    let caldco = p.CALIBRATION_DATA.caldco_1mhz.read().caldco_1mhz().bits();
 
     // We want a nominally 1.6MHz clock (to get an easily-divisible timer of
     // 100kHz). Higher frequencies are fine, but even a bit lower than 1.6MHz
@@ -142,44 +521,140 @@ fn init(cs: CriticalSection) {
     // value by 1.35^2*1.08. This is closer to 1.70MHz; we add some breathing
     // room because the 1MHz calibration value can vary up to 3% according to
     // the MSP430G2211 datasheet.
-    p.SYSTEM_CLOCK
-        .bcsctl1
-        .write(|w| w.bcsctl1().bits(calcb1 + 2)); // XT2 off, Multiply freq by 1.35^2.
-        // Assumes bottom 4 bits < 14, will spill into DIVA bits if violated.
-    p.SYSTEM_CLOCK.dcoctl.write(|w| {
-        w.dcoctl().bits(if caldco >= 32 {
-            caldco - 32 // Divide by 1.08 if DCO bits nonzero.
-        } else {
-            caldco // Otherwise leave alone.
-        })
+    //
+    // Erased calibration constants (0xff, e.g. on salvaged parts) would give
+    // a wildly wrong clock, so use the fixed fallback instead.
+    let (bcsctl1, dcoctl) = if calcb1 == 0xff || caldco == 0xff {
+        (FALLBACK_BCSCTL1, FALLBACK_DCOCTL)
+    } else {
+        // Multiply freq by 1.35^2. RSEL saturates rather than spilling into
+        // the DIVA bits.
+        let rsel = ((calcb1 & 0x0f) + RSEL_BOOST).min(0x0f);
+
+        (
+            (calcb1 & 0xf0) | rsel,
+            if caldco >= 32 {
+                caldco - 32 // Divide by 1.08 if DCO bits nonzero.
+            } else {
+                caldco // Otherwise leave alone.
+            },
+        )
+    };
+
+    p.SYSTEM_CLOCK.bcsctl1.write(|w| w.bcsctl1().bits(bcsctl1)); // XT2 off.
+    p.SYSTEM_CLOCK.dcoctl.write(|w| w.dcoctl().bits(dcoctl));
+    // SMCLK: DCO / SMCLK_DIV, nominally 400kHz.
+    p.SYSTEM_CLOCK.bcsctl2.write(|w| match SMCLK_DIVS {
+        0 => w.divs().divs_0(),
+        1 => w.divs().divs_1(),
+        2 => w.divs().divs_2(),
+        _ => w.divs().divs_3(),
     });
-    p.SYSTEM_CLOCK.bcsctl2.write(|w| w.divs().divs_2()); // Divide submain clock by 4, nominally 400kHz.
 
     p.TIMER_A2.taccr0.write(|w| w.taccr0().bits(0x0000));
-    p.TIMER_A2
-        .tactl
-        .write(|w| w.tassel().tassel_2().id().id_2().mc().mc_1()); // Divide by 4, use submain clock (100kHz).
+    // Timer: SMCLK / TIMER_DIV, i.e. TIMER_TARGET_HZ.
+    p.TIMER_A2.tactl.write(|w| {
+        let w = w.tassel().tassel_2();
+        let w = match TIMER_ID {
+            0 => w.id().id_0(),
+            1 => w.id().id_1(),
+            2 => w.id().id_2(),
+            _ => w.id().id_3(),
+        };
+        w.mc().mc_1()
+    });
     p.TIMER_A2.tacctl0.write(|w| w.ccie().set_bit());
 
+    if !timer_counts(&p.TIMER_A2) {
+        diagnostics::set_timer_failed(cs);
+    }
+
     let shared = At2XtPeripherals {
         port: p.PORT_1_2,
         timer: p.TIMER_A2,
     };
 
-    At2XtPeripherals::init(shared, cs).unwrap();
+    // Only fails if something already shared the peripherals. panic() can't
+    // reach them either way, and the watchdog is held, so it would hang
+    // silently; let the watchdog run again and give us a clean boot instead.
+    if At2XtPeripherals::init(shared, cs).is_err() {
+        p.WATCHDOG_TIMER.wdtctl.write(|w| w.wdtpw().password());
+
+        loop {
+            msp430::asm::nop();
+        }
+    }
+}
+
+// Stop everything and leave the converter in a known safe state: interrupts
+// off, the timer stopped, and every line released (see driver::park). Nothing
+// runs again until reset. Before init(), this only disables interrupts.
+fn safe_idle() -> Result<(), ()> {
+    // Outside the critical section, which would otherwise restore them.
+    msp430::interrupt::disable();
+
+    mspcs::with(|cs| {
+        let timer: &pac::TIMER_A2 = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+        timer.taccr0.write(|w| w.taccr0().bits(0x0000));
+        timer.tacctl0.modify(|_, w| w.ccifg().clear_bit());
+
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+        driver::park(port);
+        Ok(())
+    })
+}
+
+// A timer that doesn't count (e.g. fed from the wrong clock) would hang
+// every delay(), so check it before relying on it. Interrupts are still off
+// in init(), so watch for CCIFG rather than TIMEOUT.
+fn timer_counts(timer: &pac::TIMER_A2) -> bool {
+    // 10 ticks is 160 MCLK cycles; each poll takes a handful.
+    const CHECK_TICKS: u16 = 10;
+    const CHECK_POLLS: u16 = 1000;
+
+    timer.taccr0.write(|w| w.taccr0().bits(CHECK_TICKS));
+    let fired = (0..CHECK_POLLS).any(|_| timer.tacctl0.read().ccifg().bit_is_set());
+
+    timer.taccr0.write(|w| w.taccr0().bits(0x0000));
+    timer.tacctl0.modify(|_, w| w.ccifg().clear_bit());
+    fired
 }
 
 #[entry(interrupt_enable(pre_interrupt = init))]
 fn main() -> ! {
-    send_byte_to_at_keyboard(Cmd::RESET).unwrap();
+    #[cfg(feature = "loopback-test")]
+    if loopback_jumpered().unwrap() {
+        loopback_test();
+    }
+
+    confirm_keyboard().unwrap();
 
     let mut loop_cmd: Cmd;
     let mut loop_reply: ProcReply = ProcReply::init();
     let mut fsm_driver: Fsm = Fsm::start();
+    let mut bad_frames: u8 = 0;
+    let mut maintenance = Maintenance::new();
+    #[cfg(feature = "macros")]
+    let mut player = macros::Player::new();
+    #[cfg(any(feature = "boot-heartbeat", feature = "boot-numlock-on"))]
+    let mut boot_keys = BootKeys::new();
 
     loop {
         // Run state machine/send reply. Receive new cmd.
-        loop_cmd = fsm_driver.run(&loop_reply).unwrap();
+        // An FSM error isn't worth a panic; note it and wait for the next
+        // key.
+        loop_cmd = fsm_driver.run(&loop_reply).unwrap_or_else(|_e| {
+            mspcs::with(diagnostics::count_fsm_error);
+            eventlog::log(Event::FsmError);
+            Cmd::WaitForKey
+        });
+        INITIALIZED.store(!fsm_driver.expecting_bat(), Ordering::SeqCst);
+
+        #[cfg(feature = "profiling")]
+        let started = match loop_cmd {
+            Cmd::WaitForKey => None,
+            _ => Some(mspcs::with(now)),
+        };
 
         loop_reply = match loop_cmd {
             Cmd::ClearBuffer => {
@@ -190,31 +665,62 @@ fn main() -> ! {
                     // if-let for now and handle errors by doing nothing.
 
                     if let Ok(mut b) = IN_BUFFER.borrow(cs).try_borrow_mut() {
-                        b.flush()
+                        b.flush();
+                        end_hold_off(cs, &b);
                     }
                 });
                 ProcReply::ClearedBuffer
             }
+            #[cfg(feature = "leds")]
             Cmd::ToggleLed(m) => {
                 toggle_leds(m).unwrap();
                 ProcReply::LedToggled(m)
             }
             Cmd::SendXtKey(k) => {
-                send_byte_to_pc(k).unwrap();
+                XtPort.send_xt_key(k).unwrap();
+                maintenance.key_seen();
                 ProcReply::SentKey(k)
             }
+            Cmd::ReinitKeyboard => {
+                // After a double reset, the keyboard is still running its
+                // self-test, and would leave anything sent to it unanswered.
+                // Its 0xaa mustn't be flushed by the ClearBuffer that follows,
+                // either.
+                if fsm_driver.expecting_bat() {
+                    take_bat().unwrap();
+                }
+                reinit_keyboard().unwrap();
+                ProcReply::KeyboardReinitialized
+            }
+            Cmd::Magic(MagicAction::ReinitKeyboard) => {
+                reinit_keyboard().unwrap();
+                ProcReply::MagicDone
+            }
+            Cmd::Magic(MagicAction::NextXtProfile) => {
+                next_xt_profile();
+                #[cfg(all(feature = "scroll-lock-as-mode", feature = "leds"))]
+                show_xt_profile(fsm_driver.led_mask()).unwrap();
+                ProcReply::MagicDone
+            }
+            #[cfg(feature = "macros")]
+            Cmd::Magic(MagicAction::PlayMacro) => {
+                player.start();
+                ProcReply::MagicDone
+            }
+            #[cfg(feature = "prefer-keyboard-set1")]
+            Cmd::SelectSet1 => ProcReply::Set1Selected(select_set1().unwrap()),
+            Cmd::Idle => {
+                maintenance.tick().unwrap();
+                #[cfg(feature = "macros")]
+                player.feed(&mut fsm_driver);
+                #[cfg(any(feature = "boot-heartbeat", feature = "boot-numlock-on"))]
+                boot_keys.feed(&mut fsm_driver);
+                ProcReply::NothingToDo
+            }
             Cmd::WaitForKey => {
                 // The micro spends the majority of its life idle. It is possible for the host PC and
                 // the keyboard to send data to the micro at the same time. To keep control flow simple,
                 // the micro will only respond to host PC acknowledge requests if its idle.
-                fn reset_requested() -> bool {
-                    mspcs::with(|cs| {
-                        let port = At2XtPeripherals::periph_ref(cs).unwrap();
-
-                        driver::is_unset(port, Pins::XT_SENSE)
-                    })
-                }
-
                 fn attempt_take() -> Option<u16> {
                     mspcs::with(|cs| {
                         IN_BUFFER
@@ -222,45 +728,174 @@ fn main() -> ! {
                             .try_borrow_mut()
                             // Staying in idle state and busy-waiting is reasonable behavior for
                             // now if we couldn't borrow the IN_BUFFER.
-                            .map_or(None, |mut b| b.take())
+                            .map_or(None, |mut b| {
+                                let k = b.take();
+                                end_hold_off(cs, &b);
+                                k
+                            })
                     })
                 }
 
+                // WaitForKey is the only user of the timer while we're idle,
+                // so it doubles as the maintenance tick.
+                let mut idle = Wait::start(IDLE_TICK);
+
                 loop {
                     if let Some(b_in) = attempt_take() {
-                        let mut bits_in = b_in;
-                        bits_in &= !(0x4000 + 0x0001); // Mask out start/stop bit.
-                        bits_in >>= 2; // Remove stop bit and parity bit (FIXME: Check parity).
-                        break ProcReply::GrabbedKey((bits_in as u8).swap_bits());
+                        if let Ok(k) = keybuffer::check_at_frame(b_in) {
+                            bad_frames = 0;
+                            mspcs::with(|cs| KBD_UNANSWERED.borrow(cs).set(0));
+                            maintenance.keyboard_sent(k).unwrap();
+                            break ProcReply::GrabbedKey(k);
+                        }
+
+                        eventlog::log(Event::BadFrame);
+                        bad_frames = bad_frames.saturating_add(1);
+                        if bad_frames >= MAX_BAD_FRAMES {
+                            bad_frames = 0;
+                            reset_keyboard().unwrap();
+                            break ProcReply::KeyboardReset;
+                        }
+
+                        // A keyboard that doesn't resend gets no more
+                        // patience than one that resends garbage.
+                        if matches!(FRAMING_POLICY, FramingPolicy::Resend)
+                            && !request_resend().unwrap()
+                        {
+                            bad_frames = bad_frames.saturating_add(1);
+                        }
                     }
+                    end_xt_hold_off();
                     // If host computer wants to reset
-                    if reset_requested() {
-                        send_byte_to_at_keyboard(Cmd::RESET).unwrap();
-                        send_byte_to_pc(Cmd::SELF_TEST_PASSED).unwrap();
+                    if reset_requested() && reset_held().unwrap() {
+                        eventlog::log(Event::HostReset);
+                        reset_keyboard().unwrap();
+                        if SEND_SELF_TEST_ON_RESET {
+                            if SELF_TEST_DELAY != 0 {
+                                delay(SELF_TEST_DELAY).unwrap();
+                            }
+                            send_byte_to_pc_blocking(Cmd::SELF_TEST_PASSED).unwrap();
+                            // Nonstandard; a real XT keyboard sends nothing more.
+                            #[cfg(feature = "self-test-status")]
+                            send_byte_to_pc_blocking(mspcs::with(|cs| {
+                                let high_water = IN_BUFFER
+                                    .borrow(cs)
+                                    .try_borrow()
+                                    .map_or(0, |b| b.high_water_mark());
+                                diagnostics::status_byte(cs, high_water)
+                            }))
+                            .unwrap();
+                        }
+                        #[cfg(any(feature = "boot-heartbeat", feature = "boot-numlock-on"))]
+                        boot_keys.rearm();
                         break ProcReply::KeyboardReset;
                     }
+                    if idle.expired() {
+                        drop_stale_key_in();
+                        let absent = keyboard_absent();
+                        show_keyboard_absent(absent).unwrap();
+
+                        break if absent {
+                            ProcReply::KeyboardAbsent
+                        } else {
+                            ProcReply::IdleTick
+                        };
+                    }
                 }
             }
+        };
+
+        if matches!(
+            loop_reply,
+            ProcReply::KeyboardReset | ProcReply::KeyboardReinitialized
+        ) {
+            maintenance.keyboard_reset();
+        }
+
+        #[cfg(feature = "profiling")]
+        if let Some(start) = started {
+            mspcs::with(|cs| {
+                if let Some(t) = elapsed_since(cs, start) {
+                    diagnostics::record_loop_time(cs, t);
+                }
+            });
         }
     }
 }
 
-pub fn send_xt_bit(bit: u8) -> Result<(), ()> {
+fn xt_profile() -> XtTimingProfile {
+    mspcs::with(|cs| XT_PROFILES.get(usize::from(XT_PROFILE.borrow(cs).get())))
+        .copied()
+        .unwrap_or(XT_PROFILE_IBM)
+}
+
+#[allow(clippy::as_conversions)]
+fn next_xt_profile() {
+    mspcs::with(|cs| {
+        let profile = XT_PROFILE.borrow(cs);
+        profile.set((profile.get() + 1) % XT_PROFILES.len() as u8);
+    });
+}
+
+// scroll-lock-as-mode lights one LED per XT profile, left to right on most
+// keyboards, for MODE_LED_TIME after switching.
+#[cfg(all(feature = "scroll-lock-as-mode", feature = "leds"))]
+const PROFILE_LEDS: [LedMask; 3] = [LedMask::NUM, LedMask::CAPS, LedMask::SCROLL];
+#[cfg(all(feature = "scroll-lock-as-mode", feature = "leds"))]
+const _: () = assert!(XT_PROFILES.len() <= PROFILE_LEDS.len());
+
+// 300ms.
+#[cfg(all(feature = "scroll-lock-as-mode", feature = "leds"))]
+#[allow(clippy::as_conversions)]
+const MODE_LED_TIME: u16 = (TIMER_TARGET_HZ / 1000 * 300) as u16;
+
+// Show which XT profile is in use, then put back the lock LEDs (restore).
+#[cfg(all(feature = "scroll-lock-as-mode", feature = "leds"))]
+fn show_xt_profile(restore: LedMask) -> Result<(), ()> {
+    let lit = mspcs::with(|cs| PROFILE_LEDS.get(usize::from(XT_PROFILE.borrow(cs).get())))
+        .copied()
+        .unwrap_or(LedMask::all());
+
+    toggle_leds(lit)?;
+    delay(MODE_LED_TIME)?;
+    toggle_leds(restore)
+}
+
+fn send_xt_bit(bit: u8, profile: &XtTimingProfile) -> Result<(), ()> {
     mspcs::with(|cs| {
         let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
 
-        if bit == 1 {
-            driver::set(port, Pins::XT_DATA);
+        let (data_set, data_clear) = if bit == 1 {
+            (Pins::XT_DATA, Pins::empty())
         } else {
-            driver::unset(port, Pins::XT_DATA);
-        }
+            (Pins::empty(), Pins::XT_DATA)
+        };
+
+        // For Rising, DATA and CLK change in the same write so the host never
+        // sees CLK fall with a stale DATA.
+        let clk_clear = if matches!(profile.clock_edge, XtClockEdge::Rising) {
+            Pins::XT_CLK
+        } else {
+            Pins::empty()
+        };
 
-        driver::unset(port, Pins::XT_CLK);
+        driver::set_and_clear(port, data_set, data_clear | clk_clear);
 
         Ok(())
     })?;
 
-    delay_us!(55)?;
+    if matches!(profile.clock_edge, XtClockEdge::Falling) {
+        delay_us!(profile.half_bit_us)?;
+
+        mspcs::with(|cs| {
+            let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+            driver::unset(port, Pins::XT_CLK);
+            Ok(())
+        })?;
+    }
+
+    delay_us!(profile.half_bit_us)?;
 
     mspcs::with(|cs| {
         let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
@@ -272,8 +907,600 @@ pub fn send_xt_bit(bit: u8) -> Result<(), ()> {
     Ok(())
 }
 
-pub fn send_byte_to_pc(mut byte: u8) -> Result<(), ()> {
-    fn wait_for_host() -> Result<bool, ()> {
+#[cfg(feature = "loopback-test")]
+fn loopback_jumpered() -> Result<bool, ()> {
+    mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+        driver::pull_up(port, LOOPBACK_JUMPER);
+        Ok(())
+    })?;
+
+    delay_us!(100)?;
+
+    mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+        Ok(driver::is_unset(port, LOOPBACK_JUMPER))
+    })
+}
+
+// Factory test of the receive path, with no keyboard attached: play the
+// keyboard on the AT lines and check that PORT1, KeyIn and check_at_frame
+// get back what was sent, then that the FSM translates set 2 A, right Ctrl
+// and AltGr (make and break) to XT. A failure panics, so PANIC_LED blinks; a
+// pass lights it steadily.
+#[cfg(feature = "loopback-test")]
+fn loopback_test() -> ! {
+    const PATTERNS: [u8; 4] = [0x00, 0xff, 0xa5, 0x5a];
+    // Set 2 in, XT out. Right Ctrl and AltGr keep their E0 and take the XT
+    // codes of left Ctrl and Alt.
+    const TRANSLATIONS: [(&[u8], &[u8]); 3] = [
+        (&[0x1c, 0xf0, 0x1c], &[0x1e, 0x9e]),
+        (&[0xe0, 0x14, 0xe0, 0xf0, 0x14], &[0xe0, 0x1d, 0xe0, 0x9d]),
+        (&[0xe0, 0x11, 0xe0, 0xf0, 0x11], &[0xe0, 0x38, 0xe0, 0xb8]),
+    ];
+
+    for &byte in PATTERNS.iter() {
+        if loopback_byte(byte) != Ok(byte) {
+            panic!();
+        }
+    }
+
+    let mut fsm = Fsm::start();
+
+    for &(at, expected) in TRANSLATIONS.iter() {
+        let mut xt = XtRecorder::new();
+
+        for &byte in at.iter() {
+            if inject_at_byte(&mut fsm, byte, &mut xt).is_err() {
+                panic!();
+            }
+        }
+
+        if xt.keys() != expected {
+            panic!();
+        }
+    }
+
+    mspcs::with(|cs| {
+        if let Some(port) = At2XtPeripherals::periph_ref(cs) {
+            driver::mk_out(port, panic::PANIC_LED);
+            driver::set(port, panic::PANIC_LED);
+        }
+    });
+
+    loop {
+        msp430::asm::nop();
+    }
+}
+
+// Clock one frame in as a keyboard would: DATA changes while CLK is high,
+// and PORT1 samples it when we pull CLK low.
+#[cfg(feature = "loopback-test")]
+fn loopback_byte(byte: u8) -> Result<u8, ()> {
+    let frame = keybuffer::encode_at_frame(byte);
+
+    mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+        if let Ok(mut b) = IN_BUFFER.borrow(cs).try_borrow_mut() {
+            b.flush();
+        }
+
+        driver::at_drive(port);
+        Ok(())
+    })?;
+
+    // KeyIn shifts in MSB-first.
+    for i in (0..KeyIn::FRAME_BITS).rev() {
+        let bit = frame & (1 << i) != 0;
+
+        mspcs::with(|cs| {
+            let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+            if bit {
+                driver::set(port, Pins::AT_DATA);
+            } else {
+                driver::unset(port, Pins::AT_DATA);
+            }
+            Ok(())
+        })?;
+
+        delay_us!(40)?;
+
+        mspcs::with(|cs| {
+            let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+            driver::unset(port, Pins::AT_CLK);
+            Ok(())
+        })?;
+
+        delay_us!(40)?;
+
+        // After the last bit, PORT1 has already let go of the lines.
+        mspcs::with(|cs| {
+            let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+            driver::set(port, Pins::AT_CLK);
+            Ok(())
+        })?;
+    }
+
+    let frame = mspcs::with(|cs| {
+        IN_BUFFER
+            .borrow(cs)
+            .try_borrow_mut()
+            .map_or(None, |mut b| b.take())
+    })
+    .ok_or(())?;
+
+    keybuffer::check_at_frame(frame).map_err(|_e| {})
+}
+
+// Where translated XT keys go. On hardware, that's the XT port. Abstracting
+// it lets key handling run without one, e.g. via inject_at_byte in
+// loopback_test.
+trait XtSink {
+    fn send_xt_key(&mut self, key: u8) -> Result<(), ()>;
+}
+
+struct XtPort;
+
+impl XtSink for XtPort {
+    fn send_xt_key(&mut self, key: u8) -> Result<(), ()> {
+        send_xt_key(key)
+    }
+}
+
+// Keeps the first few XT keys sent to it, for loopback_test.
+#[cfg(feature = "loopback-test")]
+struct XtRecorder {
+    keys: [u8; 4],
+    len: u8,
+}
+
+#[cfg(feature = "loopback-test")]
+impl XtRecorder {
+    const fn new() -> XtRecorder {
+        XtRecorder {
+            keys: [0; 4],
+            len: 0,
+        }
+    }
+
+    fn keys(&self) -> &[u8] {
+        self.keys.get(..usize::from(self.len)).unwrap_or(&[])
+    }
+}
+
+#[cfg(feature = "loopback-test")]
+impl XtSink for XtRecorder {
+    // Any more than fit is a failure.
+    fn send_xt_key(&mut self, key: u8) -> Result<(), ()> {
+        *self.keys.get_mut(usize::from(self.len)).ok_or(())? = key;
+        self.len += 1;
+        Ok(())
+    }
+}
+
+// Push a raw AT byte through the same path a byte from the keyboard takes
+// (framing, decoding, FSM translation), handing the resulting XT keys to
+// sink. Commands that need the keyboard are acknowledged without touching
+// the AT port, so this works with nothing attached. The FSM must be waiting
+// for a key.
+#[cfg_attr(not(feature = "loopback-test"), allow(dead_code))]
+fn inject_at_byte<S: XtSink>(fsm: &mut Fsm, byte: u8, sink: &mut S) -> Result<(), ()> {
+    let frame = keybuffer::encode_at_frame(byte);
+    let mut reply = ProcReply::GrabbedKey(keybuffer::check_at_frame(frame).map_err(|_e| {})?);
+
+    loop {
+        reply = match fsm.run(&reply).map_err(|_e| {})? {
+            Cmd::WaitForKey => break Ok(()),
+            Cmd::SendXtKey(k) => {
+                sink.send_xt_key(k)?;
+                ProcReply::SentKey(k)
+            }
+            #[cfg(feature = "leds")]
+            Cmd::ToggleLed(m) => ProcReply::LedToggled(m),
+            Cmd::ClearBuffer => ProcReply::ClearedBuffer,
+            Cmd::ReinitKeyboard => ProcReply::KeyboardReinitialized,
+            Cmd::Idle => ProcReply::NothingToDo,
+            Cmd::Magic(_) => ProcReply::MagicDone,
+            // Nothing to read set 1 back from.
+            #[cfg(feature = "prefer-keyboard-set1")]
+            Cmd::SelectSet1 => ProcReply::Set1Selected(false),
+        };
+    }
+}
+
+// Background work, run from Cmd::Idle every IDLE_TICK while no key is
+// pending. This is the place for new periodic jobs: each gets a field here
+// for its state and a call in tick(). Jobs run with interrupts enabled and
+// may use either port, but keys wait until tick() returns, so keep them short.
+struct Maintenance {
+    #[cfg(feature = "kbd-powersave")]
+    power_save: PowerSave,
+}
+
+impl Maintenance {
+    const fn new() -> Maintenance {
+        Maintenance {
+            #[cfg(feature = "kbd-powersave")]
+            power_save: PowerSave::new(),
+        }
+    }
+
+    fn tick(&mut self) -> Result<(), ()> {
+        #[cfg(feature = "boot-replay")]
+        if host_ready() {
+            replay_pre_ready()?;
+        }
+
+        #[cfg(feature = "kbd-powersave")]
+        self.power_save.idle_tick()?;
+
+        Ok(())
+    }
+
+    // A key was sent to the host.
+    fn key_seen(&mut self) {
+        #[cfg(feature = "kbd-powersave")]
+        self.power_save.key_seen();
+    }
+
+    // The keyboard sent byte, ahead of the FSM seeing it.
+    fn keyboard_sent(&mut self, _byte: u8) -> Result<(), ()> {
+        #[cfg(feature = "kbd-powersave")]
+        self.power_save.keyboard_sent(_byte)?;
+
+        Ok(())
+    }
+
+    // The keyboard was reset or reinitialized, which leaves it scanning.
+    fn keyboard_reset(&mut self) {
+        #[cfg(feature = "kbd-powersave")]
+        self.power_save.keyboard_reset();
+    }
+}
+
+// Turns keyboard scanning off after POWERSAVE_IDLE_TICKS without a key, and
+// back on once the host shows signs of life on XT_CLK/XT_DATA, or the
+// keyboard sends something after all (e.g. one that still reports keys
+// while disabled, or one plugged back in).
+#[cfg(feature = "kbd-powersave")]
+struct PowerSave {
+    idle_ticks: u16,
+    asleep: bool,
+    xt_lines: Pins,
+}
+
+#[cfg(feature = "kbd-powersave")]
+impl PowerSave {
+    const fn new() -> PowerSave {
+        PowerSave {
+            idle_ticks: 0,
+            asleep: false,
+            xt_lines: Pins::empty(),
+        }
+    }
+
+    fn key_seen(&mut self) {
+        self.idle_ticks = 0;
+    }
+
+    // The ACK to DISABLE itself doesn't count.
+    fn keyboard_sent(&mut self, byte: u8) -> Result<(), ()> {
+        if self.asleep && byte != Cmd::ACK {
+            send_byte_to_at_keyboard(Cmd::ENABLE)?;
+            self.asleep = false;
+            self.idle_ticks = 0;
+        }
+
+        Ok(())
+    }
+
+    fn keyboard_reset(&mut self) {
+        self.asleep = false;
+        self.idle_ticks = 0;
+    }
+
+    fn idle_tick(&mut self) -> Result<(), ()> {
+        let lines = mspcs::with(|cs| {
+            At2XtPeripherals::periph_ref(cs).map(|port| driver::sample(port, Pins::XT_MASK))
+        })
+        .ok_or(())?;
+        let host_active = lines.bits() != self.xt_lines.bits();
+        self.xt_lines = lines;
+
+        if self.asleep {
+            if host_active {
+                send_byte_to_at_keyboard(Cmd::ENABLE)?;
+                self.asleep = false;
+                self.idle_ticks = 0;
+            }
+        } else {
+            self.idle_ticks = self.idle_ticks.saturating_add(1);
+
+            if self.idle_ticks >= POWERSAVE_IDLE_TICKS {
+                send_byte_to_at_keyboard(Cmd::DISABLE)?;
+                self.asleep = true;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Typed to the host (make, then break) each time it comes up, for TSRs that
+// only believe there's a keyboard once they've seen a keystroke. F12 isn't on
+// an 83-key keyboard, so software written for one should ignore it.
+#[cfg(feature = "boot-heartbeat")]
+const HEARTBEAT_KEY: u8 = 0x58;
+
+// XT hosts boot with Num Lock off, so boot-numlock-on taps it for them. The
+// FSM lights the keyboard's Num Lock LED as the release goes out.
+#[cfg(feature = "boot-numlock-on")]
+const XT_NUM_LOCK: u8 = 0x45;
+
+#[cfg(all(feature = "boot-heartbeat", feature = "boot-numlock-on"))]
+const BOOT_KEYS: [u8; 4] = [
+    XT_NUM_LOCK,
+    XT_NUM_LOCK | 0x80,
+    HEARTBEAT_KEY,
+    HEARTBEAT_KEY | 0x80,
+];
+#[cfg(all(feature = "boot-heartbeat", not(feature = "boot-numlock-on")))]
+const BOOT_KEYS: [u8; 2] = [HEARTBEAT_KEY, HEARTBEAT_KEY | 0x80];
+#[cfg(all(not(feature = "boot-heartbeat"), feature = "boot-numlock-on"))]
+const BOOT_KEYS: [u8; 2] = [XT_NUM_LOCK, XT_NUM_LOCK | 0x80];
+
+// Types BOOT_KEYS to the host each time it comes up.
+#[cfg(any(feature = "boot-heartbeat", feature = "boot-numlock-on"))]
+struct BootKeys {
+    pos: u8, // Codes of BOOT_KEYS sent so far.
+}
+
+#[cfg(any(feature = "boot-heartbeat", feature = "boot-numlock-on"))]
+impl BootKeys {
+    const fn new() -> BootKeys {
+        BootKeys { pos: 0 }
+    }
+
+    // Send the keys again, e.g. because the host just reset the keyboard.
+    fn rearm(&mut self) {
+        self.pos = 0;
+    }
+
+    // Like macros::Player::feed, one code per idle tick, but holding off
+    // until the host releases its lines.
+    fn feed(&mut self, fsm: &mut Fsm) {
+        if let Some(&code) = BOOT_KEYS.get(usize::from(self.pos)) {
+            if host_ready() && fsm.emit_xt_key(code).is_ok() {
+                self.pos = self.pos.saturating_add(1);
+            }
+        }
+    }
+}
+
+// Keys sent before the host is up (e.g. a key held down to enter setup during
+// POST) would otherwise block in send_byte_to_pc until a host reset flushes
+// them. With boot-replay, queue them instead and send them once the host
+// releases its lines.
+fn send_xt_key(key: u8) -> Result<(), ()> {
+    if !INITIALIZED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    #[cfg(feature = "boot-replay")]
+    {
+        if !host_ready() {
+            queue_pre_ready(key);
+            return Ok(());
+        }
+
+        match replay_pre_ready()? {
+            HostStatus::Ready => {}
+            // Behind the keys still waiting.
+            HostStatus::Busy => {
+                queue_pre_ready(key);
+                return Ok(());
+            }
+            // As moot as the replayed keys; WaitForKey will see the reset.
+            HostStatus::ResetHeld => return Ok(()),
+        }
+    }
+
+    xt_rate_limit()?;
+
+    #[cfg_attr(feature = "boot-replay", allow(unused_mut))]
+    let mut status = send_byte_to_pc(key)?;
+
+    // Without boot-replay, there's nowhere to park the key; keep trying,
+    // waiting a little longer each time, before giving up on it.
+    #[cfg(not(feature = "boot-replay"))]
+    {
+        let mut backoff = XT_SEND_BACKOFF;
+
+        for _ in 0..XT_SEND_RETRIES {
+            if status != HostStatus::Busy {
+                break;
+            }
+
+            delay(backoff)?;
+            backoff = backoff.saturating_mul(2);
+            status = send_byte_to_pc(key)?;
+        }
+    }
+
+    match status {
+        HostStatus::Ready => eventlog::log(Event::XtSent),
+        #[cfg(feature = "boot-replay")]
+        HostStatus::Busy => queue_pre_ready(key),
+        #[cfg(not(feature = "boot-replay"))]
+        HostStatus::Busy => mspcs::with(diagnostics::count_dropped_xt_key),
+        // On reset the key is moot; WaitForKey will see the reset and tell
+        // the FSM.
+        _ => {}
+    }
+
+    Ok(())
+}
+
+// Enforce XT_MAX_KPS: hold a key back until XT_KEY_INTERVAL has passed
+// since the last one started going out, by now(). The timer runs through the
+// idle tick and the sending of each bit, so only the odd moment between
+// waits goes uncounted, and the interval runs that much long.
+fn xt_rate_limit() -> Result<(), ()> {
+    if XT_KEY_INTERVAL == 0 {
+        return Ok(());
+    }
+
+    let rest = mspcs::with(|cs| {
+        let since = now(cs).wrapping_sub(LAST_XT_KEY.borrow(cs).get());
+        u16::try_from(since).map_or(0, |s| XT_KEY_INTERVAL.saturating_sub(s))
+    });
+
+    if rest != 0 {
+        delay(rest)?;
+    }
+
+    mspcs::with(|cs| LAST_XT_KEY.borrow(cs).set(now(cs)));
+    Ok(())
+}
+
+#[cfg(feature = "boot-replay")]
+fn queue_pre_ready(key: u8) {
+    mspcs::with(|cs| {
+        if let Ok(mut b) = PRE_READY.borrow(cs).try_borrow_mut() {
+            // Same policy as IN_BUFFER: drop keys when full.
+            #[allow(clippy::let_underscore_must_use)]
+            {
+                let _ = b.put(u16::from(key));
+            }
+        }
+    });
+}
+
+#[cfg(any(
+    feature = "boot-replay",
+    feature = "boot-heartbeat",
+    feature = "boot-numlock-on"
+))]
+fn host_ready() -> bool {
+    mspcs::with(|cs| {
+        At2XtPeripherals::periph_ref(cs)
+            .is_some_and(|port| driver::is_set(port, Pins::XT_CLK | Pins::XT_DATA))
+    })
+}
+
+// Send everything in PRE_READY, as one snapshot. If the host goes busy again,
+// what's left stays queued, in order. If it resets, none of it is sent; it
+// was all typed before the reset. Returns how the last send went.
+#[cfg(feature = "boot-replay")]
+fn replay_pre_ready() -> Result<HostStatus, ()> {
+    let mut keys = mspcs::with(|cs| {
+        PRE_READY
+            .borrow(cs)
+            .try_borrow_mut()
+            .map_or(KeycodeBuffer::new(), |mut b| b.take_all())
+    });
+
+    while let Some(k) = keys.take() {
+        match send_byte_to_pc(u8::try_from(k).map_err(|_e| {})?)? {
+            HostStatus::Ready => {}
+            HostStatus::Busy => {
+                // Nothing's been queued since the snapshot, so this can
+                // neither reorder keys nor run out of room.
+                mspcs::with(|cs| {
+                    if let Ok(mut b) = PRE_READY.borrow(cs).try_borrow_mut() {
+                        b.put(k).ok();
+                        while let Some(k) = keys.take() {
+                            b.put(k).ok();
+                        }
+                    }
+                });
+                return Ok(HostStatus::Busy);
+            }
+            HostStatus::ResetHeld => return Ok(HostStatus::ResetHeld),
+        }
+    }
+
+    Ok(HostStatus::Ready)
+}
+
+fn reset_requested() -> bool {
+    mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).unwrap();
+
+        driver::is_unset(port, Pins::XT_SENSE)
+    })
+}
+
+// The host resets the keyboard by holding XT_CLK low for 20ms. Shorter
+// pulses (some hosts poke at the lines as if to send the keyboard a command)
+// are ignored, as a real XT keyboard would. So are holds that last well past
+// a reset, until they end (see xt_held_long). Call it with XT_CLK low.
+fn reset_held() -> Result<bool, ()> {
+    // Still the hold that was already judged too long.
+    if XT_HELD_OFF.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+
+    let mut hold = Wait::start(HOST_RESET_HOLD);
+
+    while reset_requested() {
+        if hold.expired() {
+            let held = xt_held_long()?;
+            XT_HELD_OFF.store(held, Ordering::SeqCst);
+            return Ok(!held);
+        }
+    }
+
+    Ok(false)
+}
+
+// Whether XT_CLK stays low for XT_STUCK_HOLD more. A pulse that long isn't
+// a reset:
+// - An IBM PC BIOS keeps XT_CLK low through much of POST, then releases it
+//   and sends its real 20ms reset. Answering the long hold as well would
+//   see that reset as a second one in quick succession, and reinitialize
+//   the keyboard for nothing.
+// - A host that's off, or a miswired cable, holds the lines low for good.
+//   Answering that would wait forever to send SELF_TEST_PASSED.
+// Either way WaitForKey carries on, with what's left of its idle tick
+// (see Wait), and the hold can't be taken for a reset until XT_CLK has been
+// released (see end_xt_hold_off).
+fn xt_held_long() -> Result<bool, ()> {
+    let mut hold = Wait::start(XT_STUCK_HOLD);
+
+    while reset_requested() {
+        if hold.expired() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// The host let go of XT_CLK, so the next time it's pulled low, it may be a
+// reset again.
+fn end_xt_hold_off() {
+    if !reset_requested() {
+        XT_HELD_OFF.store(false, Ordering::SeqCst);
+    }
+}
+
+// What the host was doing when we tried to send it a byte.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HostStatus {
+    Ready, // The byte was sent.
+    Busy,  // Held the lines for longer than HOST_BUSY_TIMEOUT.
+    ResetHeld,
+}
+
+pub fn send_byte_to_pc(byte: u8) -> Result<HostStatus, ()> {
+    fn lines_held() -> Result<bool, ()> {
         mspcs::with(|cs| {
             let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
 
@@ -290,17 +1517,64 @@ pub fn send_byte_to_pc(mut byte: u8) -> Result<(), ()> {
 
     // The host cannot send data; the only communication it can do with the micro is pull
     // the CLK (reset) and DATA (shift register full) low.
-    // Wait for the host to release the lines.
-    while wait_for_host()? {}
+    // Wait for the host to release the lines, but not forever: a host
+    // still holding them after HOST_BUSY_TIMEOUT is either busy or, if
+    // XT_CLK is down, resetting us.
+    let mut busy = Wait::start(HOST_BUSY_TIMEOUT);
+    while lines_held()? {
+        if busy.expired() {
+            return Ok(if reset_requested() {
+                HostStatus::ResetHeld
+            } else {
+                HostStatus::Busy
+            });
+        }
+    }
+
+    fn clock_out(mut byte: u8, profile: &XtTimingProfile) -> Result<(), ()> {
+        #[cfg(feature = "xt-parity")]
+        let parity = util::compute_parity(byte);
+
+        // Nothing upstream reorders the bits of an XT code, so this is the
+        // only reversal. Parity doesn't care about the order.
+        if XT_MSB_FIRST {
+            byte = byte.swap_bits();
+        }
+
+        send_xt_bit(0, profile)?;
+        send_xt_bit(1, profile)?;
+
+        for _ in 0..8 {
+            send_xt_bit(byte & 0x01, profile)?; /* Send data... */
+            byte >>= 1;
+        }
+
+        // Not part of the XT protocol; for hosts that expect an (odd) parity
+        // bit after the data, like AT.
+        #[cfg(feature = "xt-parity")]
+        send_xt_bit(u8::from(parity), profile)?;
+
+        for _ in 0..profile.trailing_clocks {
+            send_xt_bit(1, profile)?;
+        }
 
-    send_xt_bit(0)?;
-    send_xt_bit(1)?;
+        if profile.data_hold_us != 0 {
+            delay_us!(profile.data_hold_us)?;
+        }
 
-    for _ in 0..8 {
-        send_xt_bit(byte & 0x01)?; /* Send data... */
-        byte >>= 1;
+        Ok(())
     }
 
+    let profile = xt_profile();
+
+    // Unmask whether or not the byte made it out.
+    #[cfg(feature = "strict-xt-timing")]
+    mask_at_clock()?;
+    let sent = clock_out(byte, &profile);
+    #[cfg(feature = "strict-xt-timing")]
+    unmask_at_clock()?;
+    sent?;
+
     mspcs::with(|cs| {
         let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
 
@@ -308,14 +1582,88 @@ pub fn send_byte_to_pc(mut byte: u8) -> Result<(), ()> {
         Ok(())
     })?;
 
+    Ok(HostStatus::Ready)
+}
+
+// strict-xt-timing: while an XT byte goes out, AT clock edges are left
+// pending rather than taken, so the PORT1 ISR can't stretch its bits.
+#[cfg(feature = "strict-xt-timing")]
+fn mask_at_clock() -> Result<(), ()> {
+    mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+        driver::disable_at_clk_int(port);
+        Ok(())
+    })
+}
+
+// Take AT clock interrupts again. An edge left pending means the keyboard was
+// sending, and only the last edge is remembered, so the frame has lost bits:
+// drop what KeyIn holds of it and inhibit the keyboard, which sends the frame
+// again from the start. A frame the keyboard finished while masked is lost.
+#[cfg(feature = "strict-xt-timing")]
+fn unmask_at_clock() -> Result<(), ()> {
+    let missed = mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+        let missed = driver::at_clk_int_pending(port);
+
+        if missed {
+            driver::at_inhibit(port);
+            KEY_IN.borrow(cs).set(KeyIn::new());
+            driver::clear_at_clk_int(port);
+        }
+
+        driver::enable_at_clk_int(port);
+        Ok(missed)
+    })?;
+
+    if missed {
+        delay_us!(100)?;
+
+        mspcs::with(|cs| {
+            let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+            // A full buffer wants the keyboard kept inhibited.
+            if !AT_HELD_OFF.load(Ordering::SeqCst) {
+                driver::at_idle(port);
+            }
+            Ok(())
+        })?;
+    }
+
     Ok(())
 }
 
+// For bytes the host must get, e.g. the reply to its own reset. Not at any
+// cost, though: a host that stays busy for HOST_REPLY_TRIES isn't listening,
+// and one that holds XT_CLK low again is resetting again, which WaitForKey
+// will answer in its turn.
+fn send_byte_to_pc_blocking(byte: u8) -> Result<(), ()> {
+    for _ in 0..HOST_REPLY_TRIES {
+        if send_byte_to_pc(byte)? != HostStatus::Busy {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// A host-to-keyboard transfer, start to finish:
+// 1. Inhibit: hold CLK low (and DATA high) for at least 100us, aborting
+//    anything the keyboard was sending.
+// 2. Request-to-send: pull DATA low (the start bit, KeyOut's first), then
+//    release CLK.
+// 3. The keyboard clocks in the rest of the frame. On each falling CLK edge,
+//    PORT1 puts the next bit (data 0-7, parity, stop) on DATA, and releases
+//    the lines after the stop bit.
+// 4. On the falling edge after that, the keyboard pulls DATA low to
+//    acknowledge, which PORT1 reports through host_mode::ack.
 fn send_byte_to_at_keyboard(byte: u8) -> Result<(), ()> {
-    // TODO: What does the AT keyboard protocol say about retrying xfers
-    // when inhibiting communication? Does the keyboard retry from the beginning
-    // or from the interrupted bit? Right now, we don't flush KeyIn, so
-    // we do it from the interrupted bit. This seems to work fine.
+    // A keyboard inhibited partway through a frame abandons it, and sends it
+    // again from the start bit once the lines are released. So whatever part
+    // of it KeyIn holds is stale; it's dropped once the keyboard is inhibited,
+    // before the receive path gets the lines back.
     fn wait_for_at_keyboard() -> Result<bool, ()> {
         mspcs::with(|cs| {
             let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
@@ -333,14 +1681,7 @@ fn send_byte_to_at_keyboard(byte: u8) -> Result<(), ()> {
     mspcs::with(|cs| {
         let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
 
-        let mut key_out = KEY_OUT.borrow(cs).get();
-
-        key_out.put(byte)?;
-
-        // Safe outside of critical section: As long as HOST_MODE is
-        // not set, it's not possible for the interrupt
-        // context to touch this variable.
-        KEY_OUT.borrow(cs).set(key_out);
+        host_mode::update_key_out(cs, |k| k.put(byte))?;
         driver::disable_at_clk_int(port);
         Ok(())
     })?;
@@ -354,7 +1695,15 @@ fn send_byte_to_at_keyboard(byte: u8) -> Result<(), ()> {
     mspcs::with(|cs| {
         let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
 
-        driver::unset(port, Pins::AT_DATA);
+        KEY_IN.borrow(cs).set(KeyIn::new());
+
+        // The start bit; PORT1 clocks out the rest in host mode.
+        if host_mode::update_key_out(cs, |k| k.shift_out().ok_or(()))? {
+            driver::set(port, Pins::AT_DATA);
+        } else {
+            driver::unset(port, Pins::AT_DATA);
+        }
+
         Ok(())
     })?;
 
@@ -368,18 +1717,295 @@ fn send_byte_to_at_keyboard(byte: u8) -> Result<(), ()> {
         driver::clear_at_clk_int(port);
 
         driver::enable_at_clk_int(port);
-        HOST_MODE.store(true, Ordering::SeqCst);
-        DEVICE_ACK.store(false, Ordering::SeqCst);
-        Ok(())
+        host_mode::enter(cs)
     })?;
 
-    while !DEVICE_ACK.load(Ordering::SeqCst) {}
+    // Without a keyboard, nothing will ever clock the byte out.
+    let mut reply = Wait::start(KBD_REPLY_TIMEOUT);
+    while !host_mode::acked() && !reply.expired() {}
+
+    let acked = host_mode::exit();
+
+    mspcs::with(|cs| {
+        let unanswered = KBD_UNANSWERED.borrow(cs);
+
+        if acked {
+            unanswered.set(0);
+        } else {
+            // Give up on the byte and let go of the lines.
+            host_mode::update_key_out(cs, |k| {
+                k.clear();
+                Ok(())
+            })?;
+
+            let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+            driver::at_idle(port);
+
+            unanswered.set(unanswered.get().saturating_add(1));
+        }
 
-    HOST_MODE.store(false, Ordering::SeqCst);
+        Ok(())
+    })?;
 
     Ok(())
 }
 
+// Ask the keyboard to send its last byte again, and wait for it to start
+// arriving so that it's the next frame WaitForKey takes. Returns whether it
+// did within KBD_REPLY_TIMEOUT.
+fn request_resend() -> Result<bool, ()> {
+    send_byte_to_at_keyboard(Cmd::RESEND)?;
+    let mut reply = Wait::start(KBD_REPLY_TIMEOUT);
+
+    loop {
+        let pending = mspcs::with(|cs| match IN_BUFFER.borrow(cs).try_borrow() {
+            Ok(b) => !b.is_empty(),
+            Err(_) => false,
+        });
+
+        if pending {
+            break Ok(true);
+        }
+
+        if reply.expired() {
+            break Ok(false);
+        }
+    }
+}
+
+// Let the keyboard send again if PORT1 held it off and buf has drained
+// enough. A keyboard command in the meantime releases the lines anyway; PORT1
+// holds the keyboard off again if the buffer is still full.
+fn end_hold_off(cs: CriticalSection, buf: &KeycodeBuffer) {
+    if AT_HELD_OFF.load(Ordering::SeqCst) && buf.len() <= AT_HOLD_OFF_LOW {
+        AT_HELD_OFF.store(false, Ordering::SeqCst);
+
+        if let Some(port) = At2XtPeripherals::periph_ref(cs) {
+            driver::at_idle(port);
+        }
+    }
+}
+
+fn keyboard_absent() -> bool {
+    mspcs::with(|cs| KBD_UNANSWERED.borrow(cs).get() >= KBD_ABSENT_THRESHOLD)
+}
+
+// Light PANIC_LED while the keyboard is missing.
+fn show_keyboard_absent(absent: bool) -> Result<(), ()> {
+    mspcs::with(|cs| {
+        let port = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+
+        driver::mk_out(port, panic::PANIC_LED);
+        if absent {
+            driver::set(port, panic::PANIC_LED);
+        } else {
+            driver::unset(port, panic::PANIC_LED);
+        }
+        Ok(())
+    })
+}
+
+fn reset_keyboard() -> Result<(), ()> {
+    eventlog::log(Event::KeyboardReset);
+    INITIALIZED.store(false, Ordering::SeqCst);
+    send_byte_to_at_keyboard(Cmd::RESET)
+}
+
+// Reset the keyboard until it has passed its self-test RESET_CONFIRMATIONS
+// times in a row, or RESET_ATTEMPTS resets have gone by. The last reset's
+// result is left for the FSM to take, as with any other reset; so is a
+// keyboard that doesn't answer at all, which the FSM will find missing.
+fn confirm_keyboard() -> Result<(), ()> {
+    // The FSM checks the last one.
+    let mut passed = 1;
+    let mut attempts = 1;
+
+    while passed < RESET_CONFIRMATIONS && attempts < RESET_ATTEMPTS {
+        reset_keyboard()?;
+        attempts += 1;
+
+        match take_bat()? {
+            Some(Cmd::SELF_TEST_PASSED) => passed += 1,
+            Some(_) => passed = 1,
+            None => break,
+        }
+    }
+
+    reset_keyboard()
+}
+
+// The self-test result that follows the ACK to RESET, which can take the
+// best part of a second to arrive.
+fn take_bat() -> Result<Option<u8>, ()> {
+    const BAT_TICKS: u8 = 10; // In IDLE_TICKs.
+
+    for _ in 0..BAT_TICKS {
+        match take_at_reply(IDLE_TICK)? {
+            Some(Cmd::ACK) | None => continue,
+            reply => return Ok(reply),
+        }
+    }
+
+    Ok(None)
+}
+
+// Some hosts pulse reset twice to force a full keyboard re-detection. The
+// keyboard has already been reset at this point; put it back into the state
+// a cold boot would leave it in.
+fn reinit_keyboard() -> Result<(), ()> {
+    send_byte_to_at_keyboard(Cmd::SCANCODE_SET)?;
+    delay_us!(3000)?;
+    send_byte_to_at_keyboard(0x02)?;
+    delay_us!(3000)?;
+    send_byte_to_at_keyboard(Cmd::ENABLE)
+}
+
+// Most bytes sent to the keyboard are answered with ACK alone. These are the
+// exceptions: command, the reply in ACK's place, and how many data bytes
+// follow it. Sending F0 00 also gets one, the set number (see at_reply).
+const AT_REPLIES: [(u8, u8, u8); 2] = [(Cmd::ECHO, Cmd::ECHO, 0), (Cmd::READ_ID, Cmd::ACK, 2)];
+
+// Most data bytes any command is answered with.
+const AT_REPLY_DATA: usize = 2;
+
+// What the keyboard answers cmd (and arg, if any) with: the reply to the last
+// byte sent, and how many data bytes follow it.
+const fn at_reply(cmd: u8, arg: Option<u8>) -> (u8, u8) {
+    if cmd == Cmd::SCANCODE_SET {
+        return match arg {
+            Some(0x00) => (Cmd::ACK, 1),
+            _ => (Cmd::ACK, 0),
+        };
+    }
+
+    let mut rest: &[(u8, u8, u8)] = &AT_REPLIES;
+    while let [(c, reply, data), tail @ ..] = rest {
+        if *c == cmd {
+            return (*reply, *data);
+        }
+        rest = tail;
+    }
+
+    (Cmd::ACK, 0)
+}
+
+#[allow(clippy::as_conversions)]
+const _: () = {
+    // ACK alone, with and without an argument.
+    assert!(matches!(at_reply(Cmd::ENABLE, None), (Cmd::ACK, 0)));
+    assert!(matches!(at_reply(Cmd::SET_LEDS, Some(0x02)), (Cmd::ACK, 0)));
+    assert!(matches!(
+        at_reply(Cmd::SCANCODE_SET, Some(0x01)),
+        (Cmd::ACK, 0)
+    ));
+    // No ACK at all.
+    assert!(matches!(at_reply(Cmd::ECHO, None), (Cmd::ECHO, 0)));
+    // ACK, then data.
+    assert!(matches!(at_reply(Cmd::READ_ID, None), (Cmd::ACK, 2)));
+    assert!(matches!(
+        at_reply(Cmd::SCANCODE_SET, Some(0x00)),
+        (Cmd::ACK, 1)
+    ));
+
+    let mut rest: &[(u8, u8, u8)] = &AT_REPLIES;
+    while let [(_, _, data), tail @ ..] = rest {
+        assert!(*data as usize <= AT_REPLY_DATA);
+        rest = tail;
+    }
+};
+
+// Send the keyboard a command byte, and then arg, if any, once the command
+// is acknowledged. Returns the data bytes the command is answered with (see
+// at_reply), in order and padded with zeros, or None if the keyboard didn't
+// answer as at_reply says it should: it NAKed or kept asking for a resend,
+// sent the wrong reply, or stopped short of the data (an 84-key AT keyboard
+// answers READ_ID with ACK alone). Each byte is waited on for at most
+// KBD_REPLY_TIMEOUT, so a missing one can't hang the caller. Replies are
+// taken straight off IN_BUFFER, so only use this while no keys are queued or
+// on their way, e.g. once a reset has completed.
+#[cfg_attr(not(feature = "prefer-keyboard-set1"), allow(dead_code))]
+fn send_at_command(cmd: u8, arg: Option<u8>) -> Result<Option<[u8; AT_REPLY_DATA]>, ()> {
+    let (expected, len) = at_reply(cmd, arg);
+
+    let reply = match (send_at_byte(cmd)?, arg) {
+        (Some(Cmd::ACK), Some(a)) => send_at_byte(a)?,
+        (reply, None) => reply,
+        _ => None,
+    };
+
+    if reply != Some(expected) {
+        return Ok(None);
+    }
+
+    let mut data = [0; AT_REPLY_DATA];
+    for d in data.iter_mut().take(usize::from(len)) {
+        match take_at_reply(KBD_REPLY_TIMEOUT)? {
+            Some(b) => *d = b,
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(data))
+}
+
+// One byte of send_at_command, sent again for as long as the keyboard asks,
+// up to AT_COMMAND_RETRIES times.
+#[cfg_attr(not(feature = "prefer-keyboard-set1"), allow(dead_code))]
+fn send_at_byte(byte: u8) -> Result<Option<u8>, ()> {
+    for _ in 0..=AT_COMMAND_RETRIES {
+        send_byte_to_at_keyboard(byte)?;
+
+        match take_at_reply(KBD_REPLY_TIMEOUT)? {
+            Some(Cmd::RESEND) => continue,
+            reply => return Ok(reply),
+        }
+    }
+
+    Ok(None)
+}
+
+// Ask the keyboard for scancode set 1, then ask which set it's using. Only a
+// clear "1" counts; a keyboard that NAKs, answers with anything else, or
+// doesn't answer at all stays on set 2 and the translation table.
+#[cfg(feature = "prefer-keyboard-set1")]
+fn select_set1() -> Result<bool, ()> {
+    const SET1: u8 = 0x01;
+    const QUERY_SET: u8 = 0x00;
+
+    if send_at_command(Cmd::SCANCODE_SET, Some(SET1))?.is_none() {
+        return Ok(false);
+    }
+
+    Ok(matches!(
+        send_at_command(Cmd::SCANCODE_SET, Some(QUERY_SET))?,
+        Some([SET1, _])
+    ))
+}
+
+// Wait up to timeout ticks for a byte from the keyboard. Bad frames count as
+// no reply.
+fn take_at_reply(timeout: u16) -> Result<Option<u8>, ()> {
+    let mut reply = Wait::start(timeout);
+
+    loop {
+        let frame = mspcs::with(|cs| {
+            IN_BUFFER
+                .borrow(cs)
+                .try_borrow_mut()
+                .map_or(None, |mut b| b.take())
+        });
+
+        if let Some(f) = frame {
+            break Ok(keybuffer::check_at_frame(f).ok());
+        }
+
+        if reply.expired() {
+            break Ok(None);
+        }
+    }
+}
+
+#[cfg(feature = "leds")]
 fn toggle_leds(mask: LedMask) -> Result<(), ()> {
     send_byte_to_at_keyboard(Cmd::SET_LEDS)?;
     delay_us!(3000)?;
@@ -387,19 +2013,157 @@ fn toggle_leds(mask: LedMask) -> Result<(), ()> {
     Ok(())
 }
 
+// A wait for something that may or may not happen first: start it, then
+// poll expired() while watching. Without the timer, TIMEOUT would never be
+// set, so each poll stands in for a tick instead (see tick_by_hand).
+// instant-delay always does without it.
+//
+// There's only the one timer. A wait started while this one runs (e.g.
+// reset_held's or xt_held_long's, during WaitForKey's idle tick) takes it
+// over, and this one
+// picks up where it left off once polled again, rather than expiring early.
+struct Wait {
+    until: u32,        // now() when it expires.
+    timer: Option<u8>, // TIMER_STARTS once it has the timer; None by hand.
+}
+
+impl Wait {
+    fn start(time: u16) -> Wait {
+        let timer = if cfg!(feature = "instant-delay") || mspcs::with(diagnostics::timer_failed) {
+            None
+        } else {
+            start_timer(time).ok()
+        };
+
+        mspcs::with(|cs| Wait {
+            until: now(cs).wrapping_add(u32::from(time)),
+            timer,
+        })
+    }
+
+    fn expired(&mut self) -> bool {
+        let (left, timer_ours) = mspcs::with(|cs| {
+            let left = self.until.wrapping_sub(now(cs));
+            let starts = TIMER_STARTS.borrow(cs).get();
+            (
+                u16::try_from(left).ok().filter(|&l| l != 0),
+                self.timer == Some(starts),
+            )
+        });
+
+        match (self.timer, left) {
+            (_, None) => true,
+            (Some(_), _) if timer_ours => TIMEOUT.load(Ordering::SeqCst),
+            (Some(_), Some(left)) => {
+                self.timer = start_timer(left).ok();
+                false
+            }
+            (None, Some(_)) => {
+                tick_by_hand();
+                false
+            }
+        }
+    }
+}
+
+// One timer tick, without the timer: spun out, or with instant-delay (for
+// exercising the send routines off-target) not waited at all. Either way,
+// ticks() counts it.
+fn tick_by_hand() {
+    #[cfg(not(feature = "instant-delay"))]
+    spin_delay(1);
+
+    mspcs::with(|cs| {
+        let ticks = TICKS.borrow(cs);
+        ticks.set(ticks.get().wrapping_add(1));
+    });
+}
+
 fn delay(time: u16) -> Result<(), ()> {
-    start_timer(time)?;
-    while !TIMEOUT.load(Ordering::SeqCst) {}
+    let mut wait = Wait::start(time);
+    while !wait.expired() {}
 
     Ok(())
 }
 
-fn start_timer(time: u16) -> Result<(), ()> {
+// Busy-wait for roughly `time` timer ticks without the timer. Each inner
+// iteration (nop, decrement, jump) takes about SPIN_CYCLES MCLK cycles.
+fn spin_delay(time: u16) {
+    const SPIN_CYCLES: u32 = 4;
+    #[allow(clippy::as_conversions)]
+    const SPINS_PER_TICK: u16 = (CLOCK_TARGET_HZ / TIMER_TARGET_HZ / SPIN_CYCLES) as u16;
+
+    for _ in 0..time {
+        for _ in 0..SPINS_PER_TICK {
+            msp430::asm::nop();
+        }
+    }
+}
+
+// A frame the keyboard stopped clocking partway through would have the next
+// one shifted in after its bits, so drop it once KEY_IN_TIMEOUT has passed
+// since its last edge.
+fn drop_stale_key_in() {
     mspcs::with(|cs| {
-        let timer: &msp430g2211::TIMER_A2 = At2XtPeripherals::periph_ref(cs).ok_or(())?;
+        let key_in = KEY_IN.borrow(cs);
+        let since_edge = ticks(cs).wrapping_sub(AT_LAST_EDGE.borrow(cs).get());
+
+        if !key_in.get().is_empty() && since_edge >= KEY_IN_TIMEOUT {
+            key_in.set(KeyIn::new());
+        }
+    });
+}
+
+// Timer ticks waited out since boot, for background jobs that need to know
+// how much time has passed (e.g. between probes) without a timer of their
+// own; compare two readings with wrapping_sub. The timer runs during every
+// wait, and a wait cut short has its count so far added when the next one
+// starts, so only the time between waits is missed. Without the timer,
+// waits count their ticks by hand. Wraps after about 12 hours.
+fn ticks(cs: CriticalSection) -> u32 {
+    TICKS.borrow(cs).get()
+}
+
+// ticks() plus how far the running wait has got, for timing things much
+// shorter than a wait. It only moves while the timer runs, i.e. during
+// waits, so time spent outside them isn't counted.
+fn now(cs: CriticalSection) -> u32 {
+    let into_wait = match At2XtPeripherals::periph_ref::<pac::TIMER_A2>(cs) {
+        Some(t) => t.tar.read().tar().bits(),
+        None => 0,
+    };
+
+    ticks(cs).wrapping_add(u32::from(into_wait))
+}
+
+// Ticks from start to now(), capped at u16::MAX, or None if now() went
+// backwards, and the sample is dropped. That happens when TAR has rolled
+// over to zero at the end of a wait, but the timer interrupt hasn't added
+// the wait to ticks() yet (e.g. because PORT1 is running).
+#[cfg(feature = "profiling")]
+fn elapsed_since(cs: CriticalSection, start: u32) -> Option<u16> {
+    now(cs)
+        .checked_sub(start)
+        .map(|t| u16::try_from(t).unwrap_or(u16::MAX))
+}
+
+// Start a wait of `time` ticks from now. A wait cut short leaves TAR
+// wherever it got to, and the new one would count on from there, so clear it
+// first, once ticks() has what the old one waited. Returns the new
+// TIMER_STARTS.
+fn start_timer(time: u16) -> Result<u8, ()> {
+    mspcs::with(|cs| {
+        let timer: &pac::TIMER_A2 = At2XtPeripherals::periph_ref(cs).ok_or(())?;
 
         TIMEOUT.store(false, Ordering::SeqCst);
+        let cut_short = u32::from(timer.tar.read().tar().bits());
+        let ticks = TICKS.borrow(cs);
+        ticks.set(ticks.get().wrapping_add(cut_short));
+        timer.tactl.modify(|_, w| w.taclr().set_bit());
         timer.taccr0.write(|w| w.taccr0().bits(time));
-        Ok(())
+
+        let starts = TIMER_STARTS.borrow(cs);
+        starts.set(starts.get().wrapping_add(1));
+        Ok(starts.get())
     })
 }
@@ -0,0 +1,7 @@
+// The one place that names the MCU's peripheral access crate; everything else
+// reaches the registers through mcu::pac. The firmware only uses peripherals
+// the pin-compatible G2xx parts share (PORT_1_2, TIMER_A2, the clock and
+// calibration registers), so building for a sibling with more flash should
+// only need its PAC re-exported here instead, and a memory.x with its flash
+// and RAM sizes. Nothing selects one for you.
+pub use msp430g2211 as pac;
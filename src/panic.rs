@@ -0,0 +1,48 @@
+// Instead of halting silently like panic-msp430, blink PANIC_LED forever so
+// a crashed converter can be told apart from one without power: three short
+// blinks, then a pause.
+use crate::driver::{self, Pins};
+use crate::peripheral::At2XtPeripherals;
+use core::panic::PanicInfo;
+use msp430::critical_section as mspcs;
+
+// P1.5 is otherwise unused; hang an LED (and resistor) off it to see this.
+pub const PANIC_LED: Pins = Pins::UNUSED_5;
+const PANIC_BLINKS: u8 = 3;
+// In timer ticks (10us): 150ms.
+const BLINK_TICKS: u16 = 15_000;
+// Pause after each group of blinks, in BLINK_TICKS.
+const PAUSE_BLINKS: u8 = 6;
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    // Fails before init(), but still disables interrupts.
+    crate::safe_idle().ok();
+
+    mspcs::with(|cs| {
+        // Before init() shares the peripherals, the watchdog is still running
+        // from reset and will reset us. init() holds it before then, but
+        // nothing in between panics, and init() restarts it rather than
+        // panicking if sharing them fails.
+        if let Some(port) = At2XtPeripherals::periph_ref(cs) {
+            driver::mk_out(port, PANIC_LED);
+
+            loop {
+                for _ in 0..PANIC_BLINKS {
+                    driver::set(port, PANIC_LED);
+                    crate::spin_delay(BLINK_TICKS);
+                    driver::unset(port, PANIC_LED);
+                    crate::spin_delay(BLINK_TICKS);
+                }
+
+                for _ in 0..PAUSE_BLINKS {
+                    crate::spin_delay(BLINK_TICKS);
+                }
+            }
+        }
+    });
+
+    loop {
+        msp430::asm::nop();
+    }
+}
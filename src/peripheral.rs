@@ -1,21 +1,22 @@
+use crate::mcu::pac;
 use msp430::interrupt::{CriticalSection, Mutex};
 use once_cell::unsync::OnceCell;
 
 static PERIPHERALS: Mutex<OnceCell<At2XtPeripherals>> = Mutex::new(OnceCell::new());
 
 pub struct At2XtPeripherals {
-    pub port: msp430g2211::PORT_1_2,
-    pub timer: msp430g2211::TIMER_A2,
+    pub port: pac::PORT_1_2,
+    pub timer: pac::TIMER_A2,
 }
 
-impl AsRef<msp430g2211::PORT_1_2> for At2XtPeripherals {
-    fn as_ref(&self) -> &msp430g2211::PORT_1_2 {
+impl AsRef<pac::PORT_1_2> for At2XtPeripherals {
+    fn as_ref(&self) -> &pac::PORT_1_2 {
         &self.port
     }
 }
 
-impl AsRef<msp430g2211::TIMER_A2> for At2XtPeripherals {
-    fn as_ref(&self) -> &msp430g2211::TIMER_A2 {
+impl AsRef<pac::TIMER_A2> for At2XtPeripherals {
+    fn as_ref(&self) -> &pac::TIMER_A2 {
         &self.timer
     }
 }
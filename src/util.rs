@@ -0,0 +1,17 @@
+// AT frames use odd parity: the parity bit is set when the data has an even
+// number of ones, so that data plus parity always has an odd number of ones.
+pub const fn compute_parity(byte: u8) -> bool {
+    byte.count_ones() & 0x01 == 0
+}
+
+// AT2XT has no host test harness, so known vectors are checked at compile
+// time instead. Every keyboard command byte depends on these.
+const _: () = {
+    assert!(compute_parity(0x00));
+    assert!(!compute_parity(0x01));
+    assert!(compute_parity(0xff));
+    assert!(compute_parity(0xaa));
+    assert!(compute_parity(0x55));
+    assert!(!compute_parity(0xfe));
+    assert!(compute_parity(0xed)); // SET_LEDS
+};